@@ -114,170 +114,237 @@ impl Config for CreateFilesEachSyncConfig {
     fn num_files(&self) -> usize { self.num_files }
 }
 
-pub struct CreateFiles<'a> {
+pub struct CreateFiles {
     data: CreateFilesShared,
-    base_config: &'a BaseConfiguration<'a>,
-    createfiles_config: &'a CreateFilesConfig,
+    createfiles_config: CreateFilesConfig,
 }
 
-pub struct CreateFilesBatchSync<'a> {
+pub struct CreateFilesBatchSync {
     data: CreateFilesShared,
-    base_config: &'a BaseConfiguration<'a>,
-    createfiles_config: &'a CreateFilesBatchSyncConfig,
+    createfiles_config: CreateFilesBatchSyncConfig,
 }
 
-pub struct CreateFilesEachSync<'a> {
+pub struct CreateFilesEachSync {
     data: CreateFilesShared,
-    base_config: &'a BaseConfiguration<'a>,
-    createfiles_config: &'a CreateFilesEachSyncConfig,
+    createfiles_config: CreateFilesEachSyncConfig,
 }
 
-impl<'a> CreateFiles<'a> {
-    pub fn run(base_config: &'a BaseConfiguration, createfiles_config: &'a CreateFilesConfig) -> Self {
-        let base_path = base_config.filesystem_path.join("createfiles");
+impl CreateFiles {
+    pub fn new(fs: &Filesystem) -> Self {
+        let createfiles_config = CreateFilesConfig::load("createfiles_config.json").unwrap_or(CreateFilesConfig::config_for(fs));
         Self {
-            data: CreateFilesShared::run(
-                FileSet::new(createfiles_config.num_files, &base_path, createfiles_config.dir_width),
-                &base_config.blktrace,
-                None,
-            ),
-            base_config: base_config,
+            data: CreateFilesShared::new(None),
             createfiles_config: createfiles_config,
         }
     }
 
-    pub fn export(&self) -> io::Result<()> {
+    pub fn boxed(fs: &Filesystem) -> Box<Benchmark> {
+        Box::new(Self::new(fs))
+    }
+}
+
+impl Benchmark for CreateFiles {
+    fn name(&self) -> &'static str {
+        "createfiles"
+    }
+
+    fn prepare(&mut self, base_config: &BaseConfiguration) {
+        let base_path = base_config.filesystem_path.join("createfiles");
+        self.data.prepare(FileSet::new(
+            self.createfiles_config.num_files,
+            &base_path,
+            self.createfiles_config.dir_width,
+        ));
+    }
+
+    fn run(&mut self, base_config: &BaseConfiguration) {
+        let name = self.name();
+        self.data.run(base_config, name);
+    }
+
+    fn export(&self, path: &Path) -> io::Result<()> {
         use std::fs::File;
-        let path = self.base_config.output_dir.join("createfiles");
         mkdir(&path)?;
         serde_json::to_writer(File::create(path.join("config.json"))?, &self.createfiles_config)?;
         self.data.export(path)
     }
-}
 
-impl<'a> Benchmark<CreateFilesConfig> for CreateFiles<'a> {
     fn total(&self) -> Stats {
         self.data.total()
     }
 
     fn get_trace<'b>(&'b self) -> &'b Trace {
-        &self.data.trace
+        self.data.get_trace()
     }
-    fn get_config<'b>(&'b self) -> &'b CreateFilesConfig {
-        &self.createfiles_config
+
+    fn num_files(&self) -> usize {
+        self.createfiles_config.num_files()
     }
 }
 
-impl<'a> CreateFilesBatchSync<'a> {
-    pub fn run(base_config: &'a BaseConfiguration, createfiles_config: &'a CreateFilesBatchSyncConfig) -> Self {
-        let base_path = base_config.filesystem_path.join("createfiles_batchsync");
+impl CreateFilesBatchSync {
+    pub fn new(fs: &Filesystem) -> Self {
+        let createfiles_config = CreateFilesBatchSyncConfig::load("createfiles_batchsync.json")
+            .unwrap_or(CreateFilesBatchSyncConfig::config_for(fs));
         Self {
-            data: CreateFilesShared::run(
-                FileSet::new(createfiles_config.num_files, &base_path, createfiles_config.dir_width),
-                &base_config.blktrace,
-                Some(createfiles_config.batch_size),
-            ),
-            base_config: base_config,
+            data: CreateFilesShared::new(Some(createfiles_config.batch_size)),
             createfiles_config: createfiles_config,
         }
     }
 
-    pub fn export(&self) -> io::Result<()> {
+    pub fn boxed(fs: &Filesystem) -> Box<Benchmark> {
+        Box::new(Self::new(fs))
+    }
+}
+
+impl Benchmark for CreateFilesBatchSync {
+    fn name(&self) -> &'static str {
+        "createfiles_batchsync"
+    }
+
+    fn prepare(&mut self, base_config: &BaseConfiguration) {
+        let base_path = base_config.filesystem_path.join("createfiles_batchsync");
+        self.data.prepare(FileSet::new(
+            self.createfiles_config.num_files,
+            &base_path,
+            self.createfiles_config.dir_width,
+        ));
+    }
+
+    fn run(&mut self, base_config: &BaseConfiguration) {
+        let name = self.name();
+        self.data.run(base_config, name);
+    }
+
+    fn export(&self, path: &Path) -> io::Result<()> {
         use std::fs::File;
-        let path = self.base_config.output_dir.join("createfiles_batchsync");
         mkdir(&path)?;
         serde_json::to_writer(File::create(path.join("config.json"))?, &self.createfiles_config)?;
         self.data.export(path)
     }
-}
 
-impl<'a> Benchmark<CreateFilesBatchSyncConfig> for CreateFilesBatchSync<'a> {
     fn total(&self) -> Stats {
         self.data.total()
     }
 
     fn get_trace<'b>(&'b self) -> &'b Trace {
-        &self.data.trace
+        self.data.get_trace()
     }
 
-    fn get_config<'b>(&'b self) -> &'b CreateFilesBatchSyncConfig {
-        &self.createfiles_config
+    fn num_files(&self) -> usize {
+        self.createfiles_config.num_files()
     }
 }
 
-impl<'a> CreateFilesEachSync<'a> {
-    pub fn run(base_config: &'a BaseConfiguration, createfiles_config: &'a CreateFilesEachSyncConfig) -> Self {
-        let base_path = base_config.filesystem_path.join("createfiles_eachsync");
+impl CreateFilesEachSync {
+    pub fn new(fs: &Filesystem) -> Self {
+        let createfiles_config = CreateFilesEachSyncConfig::load("createfiles_eachsync.json")
+            .unwrap_or(CreateFilesEachSyncConfig::config_for(fs));
         Self {
-            data: CreateFilesShared::run(
-                FileSet::new(createfiles_config.num_files, &base_path, createfiles_config.dir_width),
-                &base_config.blktrace,
-                Some(0),
-            ),
-            base_config: base_config,
+            data: CreateFilesShared::new(Some(0)),
             createfiles_config: createfiles_config,
         }
     }
 
-    pub fn export(&self) -> io::Result<()> {
+    pub fn boxed(fs: &Filesystem) -> Box<Benchmark> {
+        Box::new(Self::new(fs))
+    }
+}
+
+impl Benchmark for CreateFilesEachSync {
+    fn name(&self) -> &'static str {
+        "createfiles_eachsync"
+    }
+
+    fn prepare(&mut self, base_config: &BaseConfiguration) {
+        let base_path = base_config.filesystem_path.join("createfiles_eachsync");
+        self.data.prepare(FileSet::new(
+            self.createfiles_config.num_files,
+            &base_path,
+            self.createfiles_config.dir_width,
+        ));
+    }
+
+    fn run(&mut self, base_config: &BaseConfiguration) {
+        let name = self.name();
+        self.data.run(base_config, name);
+    }
+
+    fn export(&self, path: &Path) -> io::Result<()> {
         use std::fs::File;
-        let path = self.base_config.output_dir.join("createfiles_eachsync");
         mkdir(&path)?;
         serde_json::to_writer(File::create(path.join("config.json"))?, &self.createfiles_config)?;
         self.data.export(path)
     }
-}
 
-impl<'a> Benchmark<CreateFilesEachSyncConfig> for CreateFilesEachSync<'a> {
     fn total(&self) -> Stats {
         self.data.total()
     }
 
     fn get_trace<'b>(&'b self) -> &'b Trace {
-        &self.data.trace
+        self.data.get_trace()
     }
 
-    fn get_config<'b>(&'b self) -> &'b CreateFilesEachSyncConfig {
-        &self.createfiles_config
+    fn num_files(&self) -> usize {
+        self.createfiles_config.num_files()
     }
 }
 
 struct CreateFilesShared {
-    open: Stats,
-    close: Stats,
-    fsync: Stats,
-    sync: Stats,
-    trace: Trace,
+    batch_size: Option<usize>,
+    file_set: Vec<PathBuf>,
+    open: Open,
+    close: Close,
+    fsync: Fsync,
+    sync: Sync,
+    trace: Option<Trace>,
 }
 
 impl CreateFilesShared {
-    pub fn run(file_set: FileSet, blktrace: &Blktrace, batch_size: Option<usize>) -> Self {
+    fn new(batch_size: Option<usize>) -> Self {
+        Self {
+            batch_size: batch_size,
+            file_set: Vec::new(),
+            open: Open::new(),
+            close: Close::new(),
+            fsync: Fsync::new(),
+            sync: Sync::new(),
+            trace: None,
+        }
+    }
+
+    fn prepare(&mut self, file_set: FileSet) {
         use super::rand;
         use rand::Rng;
-        use std::os::unix::io::RawFd;
 
         drop_cache();
-        let file_set: Vec<PathBuf> = {
+        self.file_set = {
             let mut f: Vec<PathBuf> = file_set.into_iter().collect();
             rand::thread_rng().shuffle(&mut f);
             f
         };
-        let mut open = Open::new();
-        let mut close = Close::new();
-        let mut fsync = Fsync::new();
-        let mut sync = Sync::new();
 
-        for file in &file_set {
+        for file in &self.file_set {
             let parent_path = file.parent().expect("file should have parent");
             mkdir(parent_path).expect("failed to construct directory tree");
         }
+    }
+
+    fn run(&mut self, base_config: &BaseConfiguration, name: &str) {
+        use std::os::unix::io::RawFd;
 
-        let trace = blktrace
-            .record_with(|| {
+        let batch_size = self.batch_size;
+        let file_set = &self.file_set;
+        let open = &mut self.open;
+        let close = &mut self.close;
+        let fsync = &mut self.fsync;
+        let sync = &mut self.sync;
+        let trace = base_config
+            .record_with(name, || {
                 // Create directory structure and files
                 let mut fd_queue: Vec<(RawFd, &Path)> = Vec::new();
                 fd_queue.reserve(batch_size.unwrap_or(0));
-                for file in &file_set {
+                for file in file_set {
                     let parent_path = file.parent().expect("file should have parent");
                     assert!(parent_path.is_dir());
                     let fd = open.run(
@@ -318,39 +385,37 @@ impl CreateFilesShared {
             .expect("failed to record trace");
 
         info!("Finished micro-create:");
-        let open_stats = open.get_stats();
-        let close_stats = close.get_stats();
-        let fsync_stats = fsync.get_stats();
-        let sync_stats = sync.get_stats();
+        let open_stats = self.open.get_stats();
+        let close_stats = self.close.get_stats();
+        let fsync_stats = self.fsync.get_stats();
+        let sync_stats = self.sync.get_stats();
         info!(" - Open: {}", open_stats);
         info!(" - Close: {}", close_stats);
         info!(" - Fsync: {}", fsync_stats);
         info!(" - Sync: {}", sync_stats);
         info!(
             " - Total: {}",
-            open_stats.clone() + close_stats.clone() + fsync_stats.clone() + sync_stats.clone()
+            open_stats + close_stats + fsync_stats + sync_stats
         );
         info!(" - Blktrace recorded {} bytes on {} cpus", trace.total_bytes(), trace.num_cpus());
-        drop_cache();
-        Self {
-            open: open_stats,
-            close: close_stats,
-            fsync: fsync_stats,
-            sync: sync_stats,
-            trace: trace,
-        }
+        self.trace = Some(trace);
     }
 
     fn export<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
         use std::fs::File;
-        serde_json::to_writer(File::create(path.as_ref().join("open.json"))?, &self.open)?;
-        serde_json::to_writer(File::create(path.as_ref().join("close.json"))?, &self.close)?;
-        serde_json::to_writer(File::create(path.as_ref().join("fsync.json"))?, &self.fsync)?;
-        serde_json::to_writer(File::create(path.as_ref().join("sync.json"))?, &self.sync)?;
-        self.trace.export(&path, &"blktrace")
+        let origin = self.get_trace().start_time();
+        serde_json::to_writer(File::create(path.as_ref().join("open.json"))?, &self.open.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.as_ref().join("close.json"))?, &self.close.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.as_ref().join("fsync.json"))?, &self.fsync.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.as_ref().join("sync.json"))?, &self.sync.get_stats().with_origin(origin))?;
+        self.get_trace().export(&path, &"blktrace")
+    }
+
+    fn get_trace(&self) -> &Trace {
+        self.trace.as_ref().expect("benchmark has not been run")
     }
 
     fn total(&self) -> Stats {
-        self.open.clone() + self.close.clone() + self.fsync.clone() + self.sync.clone()
+        self.open.get_stats() + self.close.get_stats() + self.fsync.get_stats() + self.sync.get_stats()
     }
 }
@@ -12,13 +12,13 @@ use std::io;
 
 use std::path::{Path, PathBuf};
 
-pub struct DeleteFiles<'a> {
-    open: Stats,
-    close: Stats,
-    unlink: Stats,
-    trace: Trace,
-    base_config: &'a BaseConfiguration<'a>,
-    deletefiles_config: &'a DeleteFilesConfig,
+pub struct DeleteFiles {
+    file_set: Vec<PathBuf>,
+    open: Open,
+    close: Close,
+    unlink: Unlink,
+    trace: Option<Trace>,
+    deletefiles_config: DeleteFilesConfig,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -50,84 +50,103 @@ impl Config for DeleteFilesConfig {
     fn num_files(&self) -> usize { self.num_files }
 }
 
-impl<'a> DeleteFiles<'a> {
-    pub fn run(base_config: &'a BaseConfiguration, config: &'a DeleteFilesConfig) -> Self {
+impl DeleteFiles {
+    pub fn new(fs: &Filesystem) -> Self {
+        Self {
+            file_set: Vec::new(),
+            open: Open::new(),
+            close: Close::new(),
+            unlink: Unlink::new(),
+            trace: None,
+            deletefiles_config: DeleteFilesConfig::load("deletefiles_config.json").unwrap_or(DeleteFilesConfig::config_for(fs)),
+        }
+    }
+
+    pub fn boxed(fs: &Filesystem) -> Box<Benchmark> {
+        Box::new(Self::new(fs))
+    }
+}
+
+impl Benchmark for DeleteFiles {
+    fn name(&self) -> &'static str {
+        "deletefiles"
+    }
+
+    fn prepare(&mut self, base_config: &BaseConfiguration) {
         use super::rand;
         use rand::Rng;
 
         drop_cache();
+        let config = &self.deletefiles_config;
         let config_path: &Path = base_config.filesystem_path.as_ref();
         let base_path = PathBuf::from(config_path.join("delete"));
         let file_set: Vec<PathBuf> = FileSet::new(config.num_files, &base_path, config.dir_width).into_iter().collect();
-        let mut file_set_shuffled = file_set.clone();
-        rand::thread_rng().shuffle(&mut file_set_shuffled);
-        let mut open = Open::new();
-        let mut close = Close::new();
-        let mut unlink = Unlink::new();
 
-        for file in file_set {
+        for file in &file_set {
             if let Some(parent_path) = file.parent() {
                 mkdir(parent_path).expect("failed to construct directory tree");
                 assert!(parent_path.is_dir());
-                let fd = open.run(&file, nix::fcntl::OFlag::O_CREAT, nix::sys::stat::Mode::S_IRWXU)
+                let fd = self.open.run(file, nix::fcntl::OFlag::O_CREAT, nix::sys::stat::Mode::S_IRWXU)
                     .expect("failed to create file");
-                close.run(fd).expect("failed to close file");
+                self.close.run(fd).expect("failed to close file");
             }
         }
 
+        let mut file_set_shuffled = file_set;
+        rand::thread_rng().shuffle(&mut file_set_shuffled);
+        self.file_set = file_set_shuffled;
         drop_cache();
+    }
 
+    fn run(&mut self, base_config: &BaseConfiguration) {
+        let name = self.name();
+        let file_set_shuffled = &self.file_set;
+        let unlink = &mut self.unlink;
         let trace = base_config
-            .blktrace
-            .record_with(|| {
-                for file in &file_set_shuffled {
+            .record_with(name, || {
+                for file in file_set_shuffled {
                     unlink.run(file).expect("failed to unlink file");
                 }
             })
             .expect("failed to record trace");
 
         info!("Finished micro-delete:");
-        let open_stats = open.get_stats();
-        let close_stats = close.get_stats();
-        let unlink_stats = unlink.get_stats();
+        let open_stats = self.open.get_stats();
+        let close_stats = self.close.get_stats();
+        let unlink_stats = self.unlink.get_stats();
         info!(" - Open: {}", open_stats);
         info!(" - Close: {}", close_stats);
         info!(" - Unlink: {}", unlink_stats);
-        info!(" - Total: {}", open_stats.clone() + close_stats.clone() + unlink_stats.clone());
+        info!(" - Total: {}", open_stats + close_stats + unlink_stats);
         info!(" - Blktrace recorded {} bytes on {} cpus", trace.total_bytes(), trace.num_cpus());
-        drop_cache();
-        Self {
-            open: open_stats,
-            close: close_stats,
-            unlink: unlink_stats,
-            trace: trace,
-            base_config: base_config,
-            deletefiles_config: config,
-        }
+        self.trace = Some(trace);
     }
 
-    pub fn export(&self) -> io::Result<()> {
-        let path = self.base_config.output_dir.join("deletefiles");
+    fn export(&self, path: &Path) -> io::Result<()> {
         use std::fs::File;
         mkdir(&path)?;
-        serde_json::to_writer(File::create(path.join("open.json"))?, &self.open)?;
-        serde_json::to_writer(File::create(path.join("close.json"))?, &self.close)?;
-        serde_json::to_writer(File::create(path.join("unlink.json"))?, &self.unlink)?;
+        let origin = self.get_trace().start_time();
+        serde_json::to_writer(File::create(path.join("open.json"))?, &self.open.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.join("close.json"))?, &self.close.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.join("unlink.json"))?, &self.unlink.get_stats().with_origin(origin))?;
         serde_json::to_writer(File::create(path.join("config.json"))?, &self.deletefiles_config)?;
-        self.trace.export(&path, &"blktrace")
+        self.get_trace().export(&path, &"blktrace")
     }
-}
 
-impl<'a> Benchmark<DeleteFilesConfig> for DeleteFiles<'a> {
     fn total(&self) -> Stats {
-        self.open.clone() + self.close.clone() + self.unlink.clone()
+        self.open.get_stats() + self.close.get_stats() + self.unlink.get_stats()
+    }
+
+    // The opens and closes only create the files in `prepare`
+    fn measured(&self) -> Stats {
+        self.unlink.get_stats()
     }
 
     fn get_trace<'b>(&'b self) -> &'b Trace {
-        &self.trace
+        self.trace.as_ref().expect("benchmark has not been run")
     }
 
-    fn get_config<'b>(&'b self) -> &'b DeleteFilesConfig {
-        &self.deletefiles_config
+    fn num_files(&self) -> usize {
+        self.deletefiles_config.num_files()
     }
 }
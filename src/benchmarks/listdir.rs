@@ -13,13 +13,13 @@ use rand::Rng;
 use std::io;
 use std::path::{Path, PathBuf};
 
-pub struct ListDir<'a> {
-    open: Stats,
-    close: Stats,
-    readdir: Stats,
-    trace: Trace,
-    base_config: &'a BaseConfiguration<'a>,
-    listdir_config: &'a ListDirConfig,
+pub struct ListDir {
+    directories: Vec<PathBuf>,
+    open: Open,
+    close: Close,
+    readdir: ReadDir,
+    trace: Option<Trace>,
+    listdir_config: ListDirConfig,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,86 +51,101 @@ impl Config for ListDirConfig {
     fn num_files(&self) -> usize { self.num_files }
 }
 
-impl<'a> ListDir<'a> {
-    pub fn run(base_config: &'a BaseConfiguration, config: &'a ListDirConfig) -> Self {
+impl ListDir {
+    pub fn new(fs: &Filesystem) -> Self {
+        Self {
+            directories: Vec::new(),
+            open: Open::new(),
+            close: Close::new(),
+            readdir: ReadDir::new(),
+            trace: None,
+            listdir_config: ListDirConfig::load("listdir_config.json").unwrap_or(ListDirConfig::config_for(fs)),
+        }
+    }
+
+    pub fn boxed(fs: &Filesystem) -> Box<Benchmark> {
+        Box::new(Self::new(fs))
+    }
+}
+
+impl Benchmark for ListDir {
+    fn name(&self) -> &'static str {
+        "listdir"
+    }
+
+    fn prepare(&mut self, base_config: &BaseConfiguration) {
         drop_cache();
+        let config = &self.listdir_config;
         let config_path: &Path = base_config.filesystem_path.as_ref();
         let base_path = PathBuf::from(config_path.join("delete"));
         let file_set: Vec<PathBuf> = FileSet::new(config.num_files, &base_path, config.dir_width).into_iter().collect();
-        let mut directories = Vec::<PathBuf>::new();
-        let mut file_set_shuffled = file_set.clone();
-        rand::thread_rng().shuffle(&mut file_set_shuffled);
-        let mut open = Open::new();
-        let mut close = Close::new();
-        let mut readdir = ReadDir::new();
 
         for file in file_set {
             if let Some(parent_path) = file.parent() {
                 mkdir(parent_path).expect("failed to construct directory tree");
                 assert!(parent_path.is_dir());
-                directories.push(parent_path.to_owned());
-                let fd = open.run(&file, nix::fcntl::OFlag::O_CREAT, nix::sys::stat::Mode::S_IRWXU)
+                self.directories.push(parent_path.to_owned());
+                let fd = self.open.run(&file, nix::fcntl::OFlag::O_CREAT, nix::sys::stat::Mode::S_IRWXU)
                     .expect("failed to create file");
-                close.run(fd).expect("failed to close file");
+                self.close.run(fd).expect("failed to close file");
             }
         }
 
         drop_cache();
+    }
 
+    fn run(&mut self, base_config: &BaseConfiguration) {
+        let name = self.name();
+        let directories = &self.directories;
+        let readdir = &mut self.readdir;
         let trace = base_config
-            .blktrace
-            .record_with(|| {
+            .record_with(name, || {
                 const ITERATIONS: usize = 1000000;
                 for _ in 0..ITERATIONS {
                     let directory = rand::thread_rng()
-                        .choose(&directories)
+                        .choose(directories)
                         .expect("failed to randomly select directory");
                     readdir.run(directory).expect("failed to read directory");
                 }
             })
             .expect("failed to record trace");
 
-        let open_stats = open.get_stats();
-        let close_stats = close.get_stats();
-        let readdir_stats = readdir.get_stats();
+        let open_stats = self.open.get_stats();
+        let close_stats = self.close.get_stats();
+        let readdir_stats = self.readdir.get_stats();
         info!(" - Open: {}", open_stats);
         info!(" - Close: {}", close_stats);
         info!(" - Readdir: {}", readdir_stats);
-        info!(" - Total: {}", open_stats.clone() + close_stats.clone() + readdir_stats.clone());
+        info!(" - Total: {}", open_stats + close_stats + readdir_stats);
         info!(" - Blktrace recorded {} bytes on {} cpus", trace.total_bytes(), trace.num_cpus());
-        drop_cache();
-        Self {
-            open: open_stats,
-            close: close_stats,
-            readdir: readdir_stats,
-            trace: trace,
-            base_config: base_config,
-            listdir_config: config,
-        }
+        self.trace = Some(trace);
     }
 
-    pub fn export(&self) -> io::Result<()> {
-        let path = self.base_config.output_dir.join("listdir");
+    fn export(&self, path: &Path) -> io::Result<()> {
         use std::fs::File;
         mkdir(&path)?;
-        serde_json::to_writer(File::create(path.join("open.json"))?, &self.open)?;
-        serde_json::to_writer(File::create(path.join("close.json"))?, &self.close)?;
-        serde_json::to_writer(File::create(path.join("readdir.json"))?, &self.readdir)?;
+        let origin = self.get_trace().start_time();
+        serde_json::to_writer(File::create(path.join("open.json"))?, &self.open.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.join("close.json"))?, &self.close.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.join("readdir.json"))?, &self.readdir.get_stats().with_origin(origin))?;
         serde_json::to_writer(File::create(path.join("config.json"))?, &self.listdir_config)?;
-        self.trace.export(&path, &"blktrace")
+        self.get_trace().export(&path, &"blktrace")
     }
-}
 
-impl<'a> Benchmark<ListDirConfig> for ListDir<'a> {
     fn total(&self) -> Stats {
-        self.open.clone() + self.close.clone() + self.readdir.clone()
+        self.open.get_stats() + self.close.get_stats() + self.readdir.get_stats()
+    }
+
+    // The opens and closes only create the files in `prepare`
+    fn measured(&self) -> Stats {
+        self.readdir.get_stats()
     }
 
     fn get_trace<'b>(&'b self) -> &'b Trace {
-        &self.trace
+        self.trace.as_ref().expect("benchmark has not been run")
     }
 
-    fn get_config<'b>(&'b self) -> &'b ListDirConfig {
-        &self.listdir_config
+    fn num_files(&self) -> usize {
+        self.listdir_config.num_files()
     }
 }
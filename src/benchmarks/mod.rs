@@ -17,16 +17,273 @@ use serde::Serialize;
 
 pub struct BaseConfiguration<'a> {
     pub filesystem_path: &'a Path,
-    pub blktrace: &'a fsbench::blktrace::Blktrace,
+    // Records the IO of each measured phase, see `--io-tracer`
+    pub tracer: &'a IoTracer,
     pub output_dir: PathBuf,
 }
 
-use fsbench::blktrace::Trace;
-use fsbench::statistics::Stats;
-pub trait Benchmark<T: Config> {
+impl<'a> BaseConfiguration<'a> {
+    // Records the IO issued by `task` for the benchmark called `name`
+    pub fn record_with<F: FnMut() -> ()>(&self, name: &str, mut task: F) -> nix::Result<Trace> {
+        self.tracer.record(&self.output_dir.join(name), &mut || (), &mut task)
+    }
+}
+
+use fsbench::blktrace::{DeviceIo, IoAttribution, IoCategories, IoPhases, Trace, TraceLoss};
+use fsbench::diskstats::{total_bytes, total_ios, DeviceStats, DiskStatsCheck};
+use fsbench::ftrace::FsEvents;
+use fsbench::statistics::{as_secs_f64, duration_to_nanos, Aggregate, Stats};
+use fsbench::tracer::IoTracer;
+use fsbench::util::drop_cache;
+use std::io;
+use std::time::Duration;
+
+// Every benchmark goes through the same lifecycle, driven by main.rs:
+//   prepare -> run (measured, traced by the IO tracer) -> teardown -> export -> summary
+// The trait is object-safe so benchmarks can be kept in a `Vec<Box<Benchmark>>`.
+pub trait Benchmark {
+    fn name(&self) -> &'static str;
+
+    // Set up the files and directories the measured phase needs. Not traced.
+    fn prepare(&mut self, base_config: &BaseConfiguration);
+
+    // The measured phase
+    fn run(&mut self, base_config: &BaseConfiguration);
+
+    fn teardown(&mut self, _base_config: &BaseConfiguration) {
+        drop_cache();
+    }
+
+    // Write the per-operation stats, config and trace into `path`
+    fn export(&self, path: &Path) -> io::Result<()>;
+
     fn total(&self) -> Stats;
     fn get_trace<'b>(&'b self) -> &'b Trace;
-    fn get_config<'b>(&'b self) -> &'b T;
+    fn num_files(&self) -> usize;
+
+    // Operations of the measured phase only, i.e. `total` without whatever `prepare` ran
+    fn measured(&self) -> Stats {
+        self.total()
+    }
+
+    // Stats of the benchmark's Write operations, if it has any. Workloads that only touch
+    // metadata (create, rename, delete, listdir) have none.
+    fn write_stats(&self) -> Option<Stats> {
+        None
+    }
+
+    // Bytes the workload asked to write through write(2)
+    fn logical_bytes_written(&self) -> usize {
+        self.write_stats().map(|stats| stats.total_bytes()).unwrap_or(0)
+    }
+
+    // Writes ops/sec, latency, completed IO and device queue depth per TIME_SERIES_WINDOW_MS window to
+    // timeseries.csv in `path`. Windows start when the measured phase starts, the same origin
+    // as the blktrace events, and cover any trailing IO that completes after the phase ends.
+    // Skipped with `--streaming-stats`, which doesn't keep the operations' start times.
+    fn export_time_series(&self, path: &Path) -> io::Result<()> {
+        use std::fs::File;
+        use std::io::{BufWriter, Write};
+        let trace = self.get_trace();
+        let window = Duration::from_millis(TIME_SERIES_WINDOW_MS);
+        let span = duration_to_nanos(trace.span());
+        let num_windows = ((span + duration_to_nanos(window) - 1) / duration_to_nanos(window)) as usize;
+        let ops = match self.total().windows(trace.start_time(), window, num_windows) {
+            Some(ops) => ops,
+            None => {
+                warn!("{}: streaming stats have no per-operation start times, not writing timeseries.csv", self.name());
+                return Ok(());
+            }
+        };
+        let io = trace.completed_bytes_windows(window, num_windows);
+        let depth = trace.queue_depth_windows(window, num_windows);
+
+        let mut file = BufWriter::new(File::create(path.join("timeseries.csv"))?);
+        writeln!(
+            file,
+            "offset_ms,ops,ops_per_second,mean_latency_ns,max_latency_ns,read_bytes,write_bytes,mean_queue_depth,max_queue_depth"
+        )?;
+        for (index, ((w, &(read_bytes, write_bytes)), &(mean_depth, max_depth))) in
+            ops.iter().zip(io.iter()).zip(depth.iter()).enumerate()
+        {
+            let mean_latency_nanos = if w.ops > 0 { w.total_latency_nanos / w.ops as u64 } else { 0 };
+            writeln!(
+                file,
+                "{},{},{},{},{},{},{},{},{}",
+                index as u64 * TIME_SERIES_WINDOW_MS,
+                w.ops,
+                w.ops as f64 / as_secs_f64(window),
+                mean_latency_nanos,
+                w.max_latency_nanos,
+                read_bytes,
+                write_bytes,
+                mean_depth,
+                max_depth
+            )?;
+        }
+        Ok(())
+    }
+
+    fn summary(&self) -> Summary {
+        let total = self.total();
+        let trace = self.get_trace();
+        let io_categories = trace.io_categories();
+        // Without blktrace the block device stats are all there is
+        let (reads, writes, io_requests) = if trace.is_traced() {
+            (trace.completed_reads(), trace.completed_writes(), trace.num_requests())
+        } else {
+            let (reads, writes) = total_bytes(trace.disk_stats());
+            (reads, writes, total_ios(trace.disk_stats()))
+        };
+        Summary {
+            name: self.name().to_owned(),
+            start_time: trace.start_time(),
+            duration: total.total_latency(),
+            io_duration: trace.io_duration(),
+            io_requests: io_requests,
+            operations: total.num_ops(),
+            reads: reads,
+            writes: writes,
+            num_files: self.num_files(),
+            iowait: total.total_iowait(),
+            write_amplification: WriteAmplification::new(
+                self.logical_bytes_written(),
+                writes,
+                &io_categories,
+                self.num_files(),
+                self.measured().num_ops(),
+            ),
+            io_categories: io_categories,
+            io_phases: trace.io_phases(),
+            devices: trace.per_device(),
+            io_by_process: trace.io_by_process(),
+            complete: trace.loss().is_complete(),
+            trace_loss: trace.loss().clone(),
+            disk_stats: trace.disk_stats().to_vec(),
+            disk_stats_check: trace.disk_stats_check(),
+            fs_events: trace.fs_events().cloned(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Summary {
+    pub name: String,
+    // CLOCK_MONOTONIC time (ns) the measured phase started: the origin of the operations' `start`
+    // offsets and of the windows in timeseries.csv and blktrace.heatmap.csv
+    pub start_time: u64,
+    pub duration: Duration,
+    pub io_duration: Duration,
+    pub io_requests: usize,
+    pub operations: usize,
+    pub reads: usize,
+    pub writes: usize,
+    pub num_files: usize,
+    // Time (ns) the benchmark's threads spent waiting for block IO, summed over all operations
+    pub iowait: usize,
+    pub io_categories: IoCategories,
+    pub write_amplification: WriteAmplification,
+    // Breakdown of the IO numbers above by when it completed: during the task, during the
+    // cooldown, or during the final flush
+    pub io_phases: IoPhases,
+    // Breakdown of the IO numbers above per traced device
+    pub devices: Vec<DeviceIo>,
+    // Which processes queued the IO; kernel threads doing writeback or journalling show up here
+    pub io_by_process: IoAttribution,
+    // false if blktrace lost events, in which case the IO numbers are too low
+    pub complete: bool,
+    pub trace_loss: TraceLoss,
+    // Block layer counters of each traced device over the recording
+    pub disk_stats: Vec<DeviceStats>,
+    // Whether blktrace and the block layer counters agree; None without both
+    pub disk_stats_check: Option<DiskStatsCheck>,
+    // Filesystem tracepoint counts and durations, e.g. journal commits per fsync; None without `--ftrace`
+    pub fs_events: Option<FsEvents>,
+}
+
+// Written bytes split by the IO categories of Trace::io_categories
+#[derive(Clone, Copy, Serialize)]
+pub struct WriteBreakdown {
+    // All completed writes, including discards
+    pub total: f64,
+    pub data: f64,
+    pub metadata: f64,
+    pub journal: f64,
+}
+
+impl WriteBreakdown {
+    // None if there is nothing to divide by
+    fn per(&self, divisor: usize) -> Option<WriteBreakdown> {
+        if divisor == 0 {
+            return None;
+        }
+        let d = divisor as f64;
+        Some(WriteBreakdown {
+            total: self.total / d,
+            data: self.data / d,
+            metadata: self.metadata / d,
+            journal: self.journal / d,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct WriteAmplification {
+    pub logical_bytes: usize,
+    pub device_bytes: WriteBreakdown,
+    // device bytes / logical bytes; None if the workload wrote no data
+    pub factor: Option<WriteBreakdown>,
+    pub per_file: Option<WriteBreakdown>,
+    // Per operation of the measured phase, see Benchmark::measured
+    pub per_operation: Option<WriteBreakdown>,
+}
+
+impl WriteAmplification {
+    fn new(logical_bytes: usize, device_bytes: usize, categories: &IoCategories, num_files: usize, num_ops: usize) -> Self {
+        let device_bytes = WriteBreakdown {
+            total: device_bytes as f64,
+            data: categories.data_writes.bytes as f64,
+            metadata: categories.metadata_writes.bytes as f64,
+            journal: categories.journal_writes.bytes as f64,
+        };
+        WriteAmplification {
+            logical_bytes: logical_bytes,
+            device_bytes: device_bytes,
+            factor: device_bytes.per(logical_bytes),
+            per_file: device_bytes.per(num_files),
+            per_operation: device_bytes.per(num_ops),
+        }
+    }
+}
+
+// Aggregated results of one benchmark that was run several times (see `--repetitions`)
+#[derive(Serialize)]
+pub struct AggregateSummary {
+    pub name: String,
+    pub repetitions: usize,
+    pub num_files: usize,
+    // Seconds
+    pub duration: Aggregate,
+    // Bytes
+    pub reads: Aggregate,
+    pub writes: Aggregate,
+    pub iowait: Aggregate,
+}
+
+impl AggregateSummary {
+    pub fn from_runs(runs: &[Summary]) -> AggregateSummary {
+        assert!(runs.len() > 0, "no runs to aggregate");
+        let collect = |f: &Fn(&Summary) -> f64| -> Aggregate { Aggregate::from_samples(&runs.iter().map(f).collect::<Vec<f64>>()) };
+        AggregateSummary {
+            name: runs[0].name.clone(),
+            repetitions: runs.len(),
+            num_files: runs[0].num_files,
+            duration: collect(&|s| as_secs_f64(s.duration)),
+            reads: collect(&|s| s.reads as f64),
+            writes: collect(&|s| s.writes as f64),
+            iowait: collect(&|s| s.iowait as f64),
+        }
+    }
 }
 
 pub trait Config : Serialize {
@@ -34,5 +291,27 @@ pub trait Config : Serialize {
     fn num_files(&self) -> usize;
 }
 
+pub struct Registration {
+    pub name: &'static str,
+    // Builds the benchmark with the configuration for the given filesystem
+    pub new: fn(&Filesystem) -> Box<Benchmark>,
+}
+
+// All benchmarks that can be selected with `--bench`, in the order they are run.
+// New workloads only need to be added here.
+pub fn registry() -> Vec<Registration> {
+    vec![
+        Registration { name: "createfiles", new: CreateFiles::boxed },
+        Registration { name: "createfiles_batchsync", new: CreateFilesBatchSync::boxed },
+        Registration { name: "createfiles_eachsync", new: CreateFilesEachSync::boxed },
+        Registration { name: "renamefiles", new: RenameFiles::boxed },
+        Registration { name: "deletefiles", new: DeleteFiles::boxed },
+        Registration { name: "listdir", new: ListDir::boxed },
+    ]
+}
+
+// Width of each row in timeseries.csv
+const TIME_SERIES_WINDOW_MS: u64 = 100;
+
 const DEFAULT_DIR_WIDTH: usize = 7;
 const DEFAULT_NUM_FILES: usize = 10000;
@@ -13,13 +13,13 @@ use rand::Rng;
 use std::io;
 use std::path::{Path, PathBuf};
 
-pub struct RenameFiles<'a> {
-    open: Stats,
-    close: Stats,
-    rename: Stats,
-    trace: Trace,
-    base_config: &'a BaseConfiguration<'a>,
-    renamefiles_config: &'a RenameFilesConfig,
+pub struct RenameFiles {
+    file_set: Vec<PathBuf>,
+    open: Open,
+    close: Close,
+    rename: Rename,
+    trace: Option<Trace>,
+    renamefiles_config: RenameFilesConfig,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,33 +51,58 @@ impl Config for RenameFilesConfig {
     fn num_files(&self) -> usize { self.num_files }
 }
 
-impl<'a> RenameFiles<'a> {
-    pub fn run(base_config: &'a BaseConfiguration, config: &'a RenameFilesConfig) -> Self {
+impl RenameFiles {
+    pub fn new(fs: &Filesystem) -> Self {
+        Self {
+            file_set: Vec::new(),
+            open: Open::new(),
+            close: Close::new(),
+            rename: Rename::new(),
+            trace: None,
+            renamefiles_config: RenameFilesConfig::load("renamefiles_config.json").unwrap_or(RenameFilesConfig::config_for(fs)),
+        }
+    }
+
+    pub fn boxed(fs: &Filesystem) -> Box<Benchmark> {
+        Box::new(Self::new(fs))
+    }
+}
+
+impl Benchmark for RenameFiles {
+    fn name(&self) -> &'static str {
+        "renamefiles"
+    }
+
+    fn prepare(&mut self, base_config: &BaseConfiguration) {
         drop_cache();
+        let config = &self.renamefiles_config;
         let config_path: &Path = base_config.filesystem_path.as_ref();
         let base_path = PathBuf::from(config_path.join("rename"));
         let file_set: Vec<PathBuf> = FileSet::new(config.num_files, &base_path, config.dir_width).into_iter().collect();
-        let mut file_set_shuffled = file_set.clone();
-        rand::thread_rng().shuffle(&mut file_set_shuffled);
-        let mut open = Open::new();
-        let mut close = Close::new();
-        let mut rename = Rename::new();
 
-        for file in file_set {
+        for file in &file_set {
             if let Some(parent_path) = file.parent() {
                 mkdir(parent_path).expect("failed to construct directory tree");
                 assert!(parent_path.is_dir());
-                let fd = open.run(&file, nix::fcntl::OFlag::O_CREAT, nix::sys::stat::Mode::S_IRWXU)
+                let fd = self.open.run(file, nix::fcntl::OFlag::O_CREAT, nix::sys::stat::Mode::S_IRWXU)
                     .expect("failed to create file");
-                close.run(fd).expect("failed to close file");
+                self.close.run(fd).expect("failed to close file");
             }
         }
 
+        let mut file_set_shuffled = file_set;
+        rand::thread_rng().shuffle(&mut file_set_shuffled);
+        self.file_set = file_set_shuffled;
         drop_cache();
+    }
+
+    fn run(&mut self, base_config: &BaseConfiguration) {
+        let name = self.name();
+        let file_set_shuffled = &self.file_set;
+        let rename = &mut self.rename;
         let trace = base_config
-            .blktrace
-            .record_with(|| {
-                for file in &file_set_shuffled {
+            .record_with(name, || {
+                for file in file_set_shuffled {
                     // Rename /path/to/file to /path/to/file.rename
                     let new_path = file.with_extension("_rename");
                     rename.run(file, &new_path).expect("failed to rename file");
@@ -86,47 +111,42 @@ impl<'a> RenameFiles<'a> {
             .expect("failed to record trace");
 
         info!("Finished micro-rename:");
-        let open_stats = open.get_stats();
-        let close_stats = close.get_stats();
-        let rename_stats = rename.get_stats();
+        let open_stats = self.open.get_stats();
+        let close_stats = self.close.get_stats();
+        let rename_stats = self.rename.get_stats();
         info!(" - Open: {}", open_stats);
         info!(" - Close: {}", close_stats);
         info!(" - Rename: {}", rename_stats);
-        info!(" - Total: {}", open_stats.clone() + close_stats.clone() + rename_stats.clone());
+        info!(" - Total: {}", open_stats + close_stats + rename_stats);
         info!(" - Blktrace recorded {} bytes on {} cpus", trace.total_bytes(), trace.num_cpus());
-        drop_cache();
-        Self {
-            open: open_stats,
-            close: close_stats,
-            rename: rename_stats,
-            trace: trace,
-            base_config: base_config,
-            renamefiles_config: config,
-        }
+        self.trace = Some(trace);
     }
 
-    pub fn export(&self) -> io::Result<()> {
-        let path = self.base_config.output_dir.join("renamefiles");
+    fn export(&self, path: &Path) -> io::Result<()> {
         use std::fs::File;
         mkdir(&path)?;
-        serde_json::to_writer(File::create(path.join("open.json"))?, &self.open)?;
-        serde_json::to_writer(File::create(path.join("close.json"))?, &self.close)?;
-        serde_json::to_writer(File::create(path.join("rename.json"))?, &self.rename)?;
+        let origin = self.get_trace().start_time();
+        serde_json::to_writer(File::create(path.join("open.json"))?, &self.open.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.join("close.json"))?, &self.close.get_stats().with_origin(origin))?;
+        serde_json::to_writer(File::create(path.join("rename.json"))?, &self.rename.get_stats().with_origin(origin))?;
         serde_json::to_writer(File::create(path.join("config.json"))?, &self.renamefiles_config)?;
-        self.trace.export(&path, &"blktrace")
+        self.get_trace().export(&path, &"blktrace")
     }
-}
 
-impl<'a> Benchmark<RenameFilesConfig> for RenameFiles<'a> {
     fn total(&self) -> Stats {
-        self.open.clone() + self.close.clone() + self.rename.clone()
+        self.open.get_stats() + self.close.get_stats() + self.rename.get_stats()
+    }
+
+    // The opens and closes only create the files in `prepare`
+    fn measured(&self) -> Stats {
+        self.rename.get_stats()
     }
 
     fn get_trace<'b>(&'b self) -> &'b Trace {
-        &self.trace
+        self.trace.as_ref().expect("benchmark has not been run")
     }
 
-    fn get_config<'b>(&'b self) -> &'b RenameFilesConfig {
-        &self.renamefiles_config
+    fn num_files(&self) -> usize {
+        self.renamefiles_config.num_files()
     }
 }
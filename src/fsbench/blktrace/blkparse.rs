@@ -0,0 +1,136 @@
+// Formats trace events the way `blkparse` does with its default output format, e.g.
+//
+//   8,0    3        1     0.000000000   697  Q  WS 223490 + 8 [kjournald]
+//
+// Columns are: device (major,minor), cpu, sequence number, time since the first event,
+// pid, action, RWBS flags, and an action-specific tail (usually sector + size and process).
+use super::pdu::{process_names, Payload};
+use super::trace::{Action, Category, Event, SECTOR_SIZE};
+use std::io;
+use std::io::Write;
+
+// Kernel dev_t encoding used in blk_io_trace
+const MINOR_BITS: u32 = 20;
+
+pub fn major(device: u32) -> u32 {
+    device >> MINOR_BITS
+}
+
+pub fn minor(device: u32) -> u32 {
+    device & ((1 << MINOR_BITS) - 1)
+}
+
+fn action_letter(action: &Action) -> Option<&'static str> {
+    match *action {
+        Action::Queue => Some("Q"),
+        Action::Backmerge => Some("M"),
+        Action::Frontmerge => Some("F"),
+        Action::GetRQ => Some("G"),
+        Action::SleepRQ => Some("S"),
+        Action::Requeue => Some("R"),
+        Action::Issue => Some("D"),
+        Action::Complete => Some("C"),
+        Action::Plug => Some("P"),
+        Action::UnplugIO => Some("U"),
+        Action::UnplugTimer => Some("UT"),
+        Action::Insert => Some("I"),
+        Action::Split => Some("X"),
+        Action::Bounce => Some("B"),
+        Action::Remap => Some("A"),
+        Action::Other | Action::Abort | Action::DrvData => None,
+    }
+}
+
+// Same rules as blkparse's fill_rwbs()
+fn rwbs(event: &Event) -> String {
+    let category = event.category;
+    let mut s = String::new();
+    if category.contains(Category::FLUSH) {
+        s.push('F');
+    }
+    if category.contains(Category::DISCARD) {
+        s.push('D');
+    } else if category.contains(Category::WRITE) {
+        s.push('W');
+    } else if event.bytes > 0 {
+        s.push('R');
+    } else {
+        s.push('N');
+    }
+    if category.contains(Category::FUA) {
+        s.push('F');
+    }
+    if category.contains(Category::AHEAD) {
+        s.push('A');
+    }
+    if category.contains(Category::SYNC) {
+        s.push('S');
+    }
+    if category.contains(Category::META) {
+        s.push('M');
+    }
+    s
+}
+
+// Writes every event in blkparse's default text format. `events` must be sorted by time.
+pub fn write_events<W: Write>(events: &[Event], out: &mut W) -> io::Result<()> {
+    let genesis = events.first().map(|event| event.time).unwrap_or(0);
+    // Process names come from notify events, which blktrace emits the first time it sees a pid
+    let names = process_names(events);
+
+    for event in events {
+        let relative = event.time.saturating_sub(genesis);
+        let header = |letter: &str| {
+            format!(
+                "{:3},{:<3} {:2} {:8} {:5}.{:09} {:5} {:>2} {:>3} ",
+                major(event.device),
+                minor(event.device),
+                event.cpu,
+                event.sequence,
+                relative / 1_000_000_000,
+                relative % 1_000_000_000,
+                event.pid,
+                letter,
+                rwbs(event)
+            )
+        };
+
+        if event.category.contains(Category::NOTIFY) {
+            if let Payload::Message(message) = event.payload() {
+                writeln!(out, "{}{}", header("m"), message)?;
+            }
+            continue;
+        }
+
+        let letter = match action_letter(&event.action) {
+            Some(letter) => letter,
+            None => continue,
+        };
+        let name = names.get(&event.pid).map(|s| s.as_str()).unwrap_or("");
+        let sectors = event.bytes as usize / SECTOR_SIZE;
+        let tail = match (&event.action, event.payload()) {
+            (&Action::Complete, _) => {
+                if event.bytes > 0 {
+                    format!("{} + {} [{}]", event.sector, sectors, event.error)
+                } else {
+                    format!("[{}]", event.error)
+                }
+            }
+            (&Action::Plug, _) => format!("[{}]", name),
+            (_, Payload::Unplug { depth }) => format!("[{}] {}", name, depth),
+            (_, Payload::Split { sector }) => format!("{} / {} [{}]", event.sector, sector, name),
+            (_, Payload::Remap { device, sector }) => {
+                format!("{} + {} <- ({},{}) {}", event.sector, sectors, major(device), minor(device), sector)
+            }
+            _ => {
+                if event.bytes > 0 {
+                    format!("{} + {} [{}]", event.sector, sectors, name)
+                } else {
+                    format!("[{}]", name)
+                }
+            }
+        };
+        writeln!(out, "{}{}", header(letter), tail)?;
+    }
+    Ok(())
+}
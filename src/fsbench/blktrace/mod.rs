@@ -1,66 +1,253 @@
 use super::nix;
+use super::super::serde_json;
+use self::trace::{Category, TraceSource};
+use std::error::Error;
+use std::io::Write;
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::str;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 type Buffer = Vec<u8>;
 
 mod trace;
-pub use self::trace::Trace;
+pub use self::processes::IoAttribution;
+pub use self::trace::{DeviceIo, IoCategories, IoPhases, Trace, TraceLoss};
 
 mod api;
+mod blkparse;
+mod pdu;
+mod processes;
+mod queue;
+mod requests;
+mod spatial;
 
-#[derive(Clone, Copy)]
+// Names accepted by `parse_trace_mask`, as in blktrace's `-a` option (which calls flush `barrier`)
+fn trace_mask_bits() -> Vec<(&'static str, Category)> {
+    vec![
+        ("read", Category::READ),
+        ("write", Category::WRITE),
+        ("flush", Category::FLUSH),
+        ("sync", Category::SYNC),
+        ("queue", Category::QUEUE),
+        ("requeue", Category::REQUEUE),
+        ("issue", Category::ISSUE),
+        ("complete", Category::COMPLETE),
+        ("fs", Category::FS),
+        ("pc", Category::PC),
+        ("notify", Category::NOTIFY),
+        ("ahead", Category::AHEAD),
+        ("meta", Category::META),
+        ("discard", Category::DISCARD),
+        ("drv_data", Category::DRV_DATA),
+        ("fua", Category::FUA),
+    ]
+}
+
+// Parses a comma-separated list of action mask names, e.g. "write,sync,meta", or "all"
+pub fn parse_trace_mask(s: &str) -> Result<u16, String> {
+    let mut mask = 0;
+    for name in s.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        if name == "all" {
+            return Ok(!0);
+        }
+        match trace_mask_bits().into_iter().find(|&(n, _)| n == name) {
+            Some((_, category)) => mask |= category.bits(),
+            None => return Err(format!("unknown trace mask `{}`", name)),
+        }
+    }
+    if mask == 0 {
+        return Err(String::from("empty trace mask"));
+    }
+    Ok(mask)
+}
+
+fn trace_mask_names(mask: u16) -> Vec<&'static str> {
+    if mask == !0 {
+        return vec!["all"];
+    }
+    trace_mask_bits()
+        .into_iter()
+        .filter(|&(_, category)| mask & category.bits() != 0)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+// Stores the trace mask as a list of names in config files
+mod trace_mask_format {
+    use serde::de::{Deserialize, Deserializer, Error};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(mask: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(super::trace_mask_names(*mask))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+        let names: Vec<String> = Vec::deserialize(deserializer)?;
+        super::parse_trace_mask(&names.join(",")).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BlktraceConfig {
+    // Size of each relay sub-buffer in bytes, per CPU. `--trace-buffer-size` takes KiB, so the
+    // JSON field spells out its unit.
+    #[serde(rename = "buffer_size_bytes")]
     buffer_size: u32,
     buffer_subbuffers: u32,
+    #[serde(with = "trace_mask_format")]
     trace_mask: u16,
+    // Only trace requests in [start_lba, end_lba) sectors; 0 = no limit
+    start_lba: u64,
+    end_lba: u64,
+    // Only trace IO issued by this process; 0 = all processes
+    pid: u32,
+    // How long record_with waits before the task (after dropping caches) for earlier IO to
+    // settle, and after the task for background writeback
+    warmup_ms: u64,
+    cooldown_ms: u64,
+    // Whether record_with syncs and drops caches after the cooldown, so IO the filesystem is
+    // still holding back (e.g. delayed allocation) ends up in the trace; and how long it
+    // then waits for that IO to complete
+    drop_cache: bool,
+    flush_wait_ms: u64,
 }
 
 impl BlktraceConfig {
-    #[allow(dead_code)]
+    // Reads a config such as {"buffer_size_bytes": 1048576, "trace_mask": ["write", "sync"]};
+    // missing fields keep their defaults
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<Error>> {
+        use std::fs::File;
+        let file = File::open(path)?;
+        let c = serde_json::from_reader(file)?;
+        Ok(c)
+    }
+
     pub fn set_trace_mask(&self, mask: u16) -> Self {
         let mut s = self.clone();
         s.trace_mask = mask;
         s
     }
 
-    #[allow(dead_code)]
     pub fn set_buffer_size(&self, buffer_size: u32) -> Self {
         let mut s = self.clone();
         s.buffer_size = buffer_size;
         s
     }
 
-    #[allow(dead_code)]
     pub fn set_buffer_subbuffers(&self, buffer_subbuffers: u32) -> Self {
         let mut s = self.clone();
         s.buffer_subbuffers = buffer_subbuffers;
         s
     }
 
-    pub fn default() -> Self {
-        return BlktraceConfig {
+    pub fn set_start_lba(&self, start_lba: u64) -> Self {
+        let mut s = self.clone();
+        s.start_lba = start_lba;
+        s
+    }
+
+    pub fn set_end_lba(&self, end_lba: u64) -> Self {
+        let mut s = self.clone();
+        s.end_lba = end_lba;
+        s
+    }
+
+    pub fn set_pid(&self, pid: u32) -> Self {
+        let mut s = self.clone();
+        s.pid = pid;
+        s
+    }
+
+    pub fn set_warmup_ms(&self, warmup_ms: u64) -> Self {
+        let mut s = self.clone();
+        s.warmup_ms = warmup_ms;
+        s
+    }
+
+    pub fn set_cooldown_ms(&self, cooldown_ms: u64) -> Self {
+        let mut s = self.clone();
+        s.cooldown_ms = cooldown_ms;
+        s
+    }
+
+    pub fn set_drop_cache(&self, drop_cache: bool) -> Self {
+        let mut s = self.clone();
+        s.drop_cache = drop_cache;
+        s
+    }
+
+    pub fn set_flush_wait_ms(&self, flush_wait_ms: u64) -> Self {
+        let mut s = self.clone();
+        s.flush_wait_ms = flush_wait_ms;
+        s
+    }
+
+    pub fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    // Whether blktrace is told to leave out some of the devices' IO, in which case the trace's
+    // totals can't be compared with the block layer counters. The kernel keeps an event if any
+    // of its categories is in the mask, so every completion is kept with `complete` or with both
+    // `read` and `write`.
+    pub fn is_filtered(&self) -> bool {
+        let mask = Category::from_bits_truncate(self.trace_mask);
+        let keeps_completions = mask.contains(Category::COMPLETE) || mask.contains(Category::READ | Category::WRITE);
+        self.pid != 0 || self.start_lba != 0 || self.end_lba != 0 || !keeps_completions
+    }
+}
+
+impl Default for BlktraceConfig {
+    fn default() -> Self {
+        BlktraceConfig {
             buffer_size: 1024 * 512,
             buffer_subbuffers: 4,
             trace_mask: !0,
-        };
+            start_lba: 0,
+            end_lba: 0,
+            pid: 0,
+            warmup_ms: 5000,
+            cooldown_ms: 2000,
+            drop_cache: true,
+            flush_wait_ms: 2000,
+        }
     }
 }
 
-pub struct Blktrace {
+// How often the reader thread wakes up to look for trace data and for new benchmark threads
+const READER_POLL_MS: i32 = 100;
+
+// Thread ids of this process right now; blktrace records the thread id as the pid of an event
+fn thread_ids() -> Vec<u32> {
+    use std::fs;
+    match fs::read_dir("/proc/self/task") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse().ok()))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// A block device being traced
+struct Device {
+    // Per-CPU relay files
     trace_paths: Vec<PathBuf>,
+    // debugfs file with the number of events dropped because the relay buffers were full
+    dropped_path: PathBuf,
     _device_path: PathBuf,
-    _device_name: String,
+    // Kernel name, e.g. "sda1"
+    device_name: String,
     _blk_setup: self::api::BlkUserTraceSetup,
     blktrace_fd: RawFd,
 }
 
-impl Blktrace {
+impl Device {
     // Path should be a block device path, e.g. /dev/sda
-    pub fn new<P: AsRef<Path>>(path: PathBuf, config: BlktraceConfig, debugfs_path: P) -> nix::Result<Self> {
+    fn new<P: AsRef<Path>>(path: PathBuf, config: &BlktraceConfig, debugfs_path: P) -> nix::Result<Self> {
         use self::api::BlkUserTraceSetup;
         use self::api::stop;
         use self::api::teardown;
@@ -74,6 +261,9 @@ impl Blktrace {
         buts.act_mask = config.trace_mask;
         buts.buf_nr = config.buffer_subbuffers;
         buts.buf_size = config.buffer_size;
+        buts.start_lba = config.start_lba;
+        buts.end_lba = config.end_lba;
+        buts.pid = config.pid;
         let fd = nix::fcntl::open(
             &path,
             nix::fcntl::OFlag::O_RDONLY | nix::fcntl::OFlag::O_NONBLOCK,
@@ -98,6 +288,7 @@ impl Blktrace {
             return Err(nix::Error::last());
         }
         let trace_directory = Path::new(debugfs_path.as_ref()).join("block").join(device_name);
+        let dropped_path = trace_directory.join("dropped");
         let mut trace_paths: Vec<PathBuf> = fs::read_dir(trace_directory)
             .expect("failed to read trace directory")
             .filter_map(|path| match path {
@@ -127,38 +318,181 @@ impl Blktrace {
             })
             .collect();
         trace_paths.sort();
-        Ok(Blktrace {
+        Ok(Device {
             trace_paths: trace_paths,
+            dropped_path: dropped_path,
             _device_path: path,
             _blk_setup: buts,
-            _device_name: device_name.to_string(),
+            device_name: device_name.to_string(),
             blktrace_fd: fd,
         })
     }
 
-    pub fn record_with<F: FnMut() -> ()>(&self, mut task: F) -> nix::Result<Trace> {
-        use super::util::drop_cache;
+    // Events dropped since tracing started, 0 if the counter can't be read
+    fn dropped(&self) -> usize {
+        use std::fs::File;
+        use std::io::Read;
+        let mut contents = String::new();
+        match File::open(&self.dropped_path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => contents.trim().parse().unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        use self::api::stop;
+        use self::api::teardown;
+        stop(self.blktrace_fd);
+        teardown(self.blktrace_fd);
+    }
+}
+
+pub struct Blktrace {
+    devices: Vec<Device>,
+    config: BlktraceConfig,
+}
+
+// What Blktrace::record hands back besides the trace data
+struct Recording<W> {
+    sinks: Vec<W>,
+    // CLOCK_MONOTONIC time (ns) at which the task started, and how long it ran
+    start_time: u64,
+    elapsed: Duration,
+    // Events the kernel dropped
+    dropped: usize,
+    // When the post-task sync + drop_caches started, if it ran
+    flush_start: Option<u64>,
+    // Every thread of this process seen while recording, including ones that have exited since
+    thread_ids: Vec<u32>,
+}
+
+impl Blktrace {
+    // Traces every device in `paths` (e.g. /dev/sda plus an external journal device) together.
+    // Their events end up in one Trace, told apart by Event::device.
+    pub fn new<P: AsRef<Path>>(paths: Vec<PathBuf>, config: BlktraceConfig, debugfs_path: P) -> nix::Result<Self> {
+        let mut devices = Vec::new();
+        for path in paths {
+            devices.push(Device::new(path, &config, debugfs_path.as_ref())?);
+        }
+        Ok(Blktrace {
+            devices: devices,
+            config: config,
+        })
+    }
+
+    // Relay files of all devices, one per device and CPU
+    fn trace_paths(&self) -> Vec<&PathBuf> {
+        self.devices.iter().flat_map(|device| device.trace_paths.iter()).collect()
+    }
+
+    // Device and CPU of each relay file, in the order of `trace_paths`. The files are called
+    // `trace<cpu>`.
+    fn trace_sources(&self) -> Vec<TraceSource> {
+        self.devices
+            .iter()
+            .flat_map(|device| {
+                device.trace_paths.iter().map(move |path| TraceSource {
+                    device: device.device_name.clone(),
+                    cpu: path.file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(|name| name.trim_start_matches("trace").parse().ok())
+                        .unwrap_or(0),
+                })
+            })
+            .collect()
+    }
+
+    // Bytes of relay buffer per CPU; the reader drains up to this much per read
+    fn read_size(&self) -> usize {
+        self.config.buffer_size as usize * self.config.buffer_subbuffers as usize
+    }
+
+    // Events dropped on all devices since tracing started
+    fn dropped(&self) -> usize {
+        self.devices.iter().fold(0, |acc, device| acc + device.dropped())
+    }
+
+    // Records the IO issued while `task` runs, keeping the trace in memory. `at_start` runs
+    // right before the task's start time is taken, after the warmup.
+    pub fn record_with<S: FnMut() -> (), F: FnMut() -> ()>(&self, at_start: S, task: F) -> nix::Result<Trace> {
+        let sinks: Vec<Buffer> = self.trace_paths().iter().map(|_| Vec::new()).collect();
+        let recording = self.record(sinks, at_start, task)?;
+        let mut trace = Trace::new(self.trace_sources(), recording.sinks, recording.start_time, recording.elapsed);
+        trace.set_dropped(recording.dropped);
+        trace.set_flush_start(recording.flush_start);
+        trace.set_benchmark_pids(recording.thread_ids);
+        trace.set_filtered(self.config.is_filtered());
+        Ok(trace)
+    }
+
+    // Records the IO issued while `task` runs, writing each device's and CPU's events to
+    // `<directory>/<prefix>.<device>.blktrace.<cpu>` as they arrive. The Trace is parsed from those
+    // files once the task is done, so the raw relay data is never buffered in memory; the
+    // decoded events the analyses run on still are. `at_start` is called as in record_with.
+    pub fn record_to<S, F>(&self, directory: &Path, prefix: &str, at_start: S, task: F) -> nix::Result<Trace>
+    where
+        S: FnMut() -> (),
+        F: FnMut() -> (),
+    {
+        use super::util::mkdir;
+        use std::fs::File;
+
+        mkdir(directory).expect("failed to create trace directory");
+        let sources = self.trace_sources();
+        let paths: Vec<PathBuf> = sources.iter().map(|source| directory.join(source.file_name(prefix))).collect();
+        let mut sinks: Vec<File> = Vec::new();
+        for path in &paths {
+            sinks.push(File::create(path).expect("failed to create trace file"));
+        }
+        let recording = self.record(sinks, at_start, task)?;
+        drop(recording.sinks);
+        let mut trace =
+            Trace::from_files(sources, paths, recording.start_time, recording.elapsed).expect("failed to read trace files");
+        trace.set_dropped(recording.dropped);
+        trace.set_flush_start(recording.flush_start);
+        trace.set_benchmark_pids(recording.thread_ids);
+        trace.set_filtered(self.config.is_filtered());
+        Ok(trace)
+    }
+
+    // Runs `task` while a reader thread copies each CPU's relay buffer into the matching sink.
+    // The warmup, cooldown and flush around the task are set in BlktraceConfig. The reader also
+    // collects the process's thread ids as it goes, so threads the task started and joined
+    // are still known to be the benchmark's; only threads that lived for less than
+    // READER_POLL_MS can be missed. The reader itself is left out.
+    fn record<W, S, F>(&self, sinks: Vec<W>, mut at_start: S, mut task: F) -> nix::Result<Recording<W>>
+    where
+        W: Write + Send + 'static,
+        S: FnMut() -> (),
+        F: FnMut() -> (),
+    {
+        use super::util::{drop_cache, monotonic_nanos};
         use nix::poll::EventFlags;
         use nix::poll::PollFd;
         use nix::poll::poll;
         use std::fs::File;
         use std::io::Read;
+        use std::collections::BTreeSet;
         use std::os::unix::io::FromRawFd;
         use std::sync::Arc;
-        use std::sync::RwLock;
         use std::sync::atomic::{AtomicBool, Ordering};
-        use std::time::Duration;
 
-        // The buffers are vectors of u8's
-        let buffers: Arc<RwLock<Vec<Buffer>>> = {
-            let mut v = Vec::new();
-            v.resize(self.trace_paths.len(), Vec::new());
-            Arc::new(RwLock::new(v))
-        };
+        // Copies everything currently in a relay file into `sink`
+        fn drain<W: Write>(file: &mut File, sink: &mut W, buffer: &mut [u8]) {
+            loop {
+                let length = file.read(buffer).expect("failed to read from trace file");
+                if length == 0 {
+                    break;
+                }
+                sink.write_all(&buffer[..length]).expect("failed to write trace data");
+            }
+        }
 
         // Open the trace files using O_NONBLOCK
         let mut file_descriptors: Vec<RawFd> = Vec::new();
-        for path in &self.trace_paths {
+        for path in self.trace_paths() {
             file_descriptors.push(nix::fcntl::open(
                 path,
                 nix::fcntl::OFlag::O_RDONLY | nix::fcntl::OFlag::O_NONBLOCK,
@@ -171,7 +505,7 @@ impl Blktrace {
 
         // Wait some time to allow IO events to accumulate
         drop_cache();
-        thread::sleep(Duration::from_millis(5000));
+        thread::sleep(Duration::from_millis(self.config.warmup_ms));
 
         // Read all events and throw them away
         let mut throwaway_data: Vec<u8> = Vec::new();
@@ -179,6 +513,7 @@ impl Blktrace {
             f.read_to_end(&mut throwaway_data).expect("failed to read events from trace file");
             throwaway_data.resize(0, 0);
         }
+        let dropped_before = self.dropped();
 
         // Used to signal the thread to cancel
         let cancel_flag = Arc::new(AtomicBool::new(false));
@@ -190,17 +525,20 @@ impl Blktrace {
             files_thread.push(f.try_clone().expect("failed to clone file!"));
         }
 
-        // moved into thread
-        let buffers_thread = buffers.clone();
+        let read_size = self.read_size();
 
-        // spawn the reader thread
+        // spawn the reader thread; it hands the sinks and the thread ids back when it is done
         let thread = thread::spawn(move || {
+            let mut sinks = sinks;
+            let reader_thread_id = i32::from(nix::unistd::gettid()) as u32;
+            let mut seen_thread_ids: BTreeSet<u32> = thread_ids().into_iter().collect();
+            let mut buffer = vec![0u8; read_size];
             // setup file descriptors for poll()
             let mut poll_file_descriptors: Vec<PollFd> = file_descriptors.iter().map(|fd| PollFd::new(*fd, EventFlags::POLLIN)).collect();
 
             while !cancel_flag_thread.load(Ordering::SeqCst) {
-                // poll the files with timeout = 500ms
-                match poll(&mut poll_file_descriptors, 500) {
+                seen_thread_ids.extend(thread_ids());
+                match poll(&mut poll_file_descriptors, READER_POLL_MS) {
                     Ok(_) => {
                         for (index, pfd) in poll_file_descriptors.iter().enumerate() {
                             if pfd.revents()
@@ -208,53 +546,56 @@ impl Blktrace {
                                 .contains(EventFlags::POLLIN)
                             {
                                 // there is data to read
-                                files_thread[index]
-                                    .read_to_end(&mut buffers_thread.write().unwrap()[index])
-                                    .expect("failed to read from trace file");
+                                drain(&mut files_thread[index], &mut sinks[index], &mut buffer);
                             }
                         }
                     }
                     Err(_) => {
-                        return;
+                        break;
                     }
                 }
             }
+            // Not the benchmark's, even if it writes streamed traces
+            seen_thread_ids.remove(&reader_thread_id);
+            (sinks, seen_thread_ids)
         });
+        at_start();
+        let start_time = monotonic_nanos();
         let start = Instant::now();
         // run the task
         task();
         let elapsed = start.elapsed();
+        let task_thread_ids = thread_ids();
 
         // wait some time to allow residual events to accumulate
-        thread::sleep(Duration::from_millis(2000));
-        drop_cache();
-        thread::sleep(Duration::from_millis(2000));
+        thread::sleep(Duration::from_millis(self.config.cooldown_ms));
+        let flush_start = if self.config.drop_cache {
+            let flush_start = monotonic_nanos();
+            drop_cache();
+            thread::sleep(Duration::from_millis(self.config.flush_wait_ms));
+            Some(flush_start)
+        } else {
+            None
+        };
 
         // stop the thread
         cancel_flag.store(true, Ordering::SeqCst);
-        thread.join().expect("failed to join thread");
+        let (mut sinks, mut seen_thread_ids) = thread.join().expect("failed to join thread");
+        seen_thread_ids.extend(task_thread_ids);
 
-        for (index, mut file) in files.iter().enumerate() {
-            file.read_to_end(&mut buffers.write().unwrap()[index])
-                .expect("failed to read from trace file");
+        let mut buffer = vec![0u8; self.read_size()];
+        for (index, mut file) in files.into_iter().enumerate() {
+            drain(&mut file, &mut sinks[index], &mut buffer);
         }
+        let dropped = self.dropped().saturating_sub(dropped_before);
 
-        // move the buffers out of the Arc<RwLock<_>> and into a Trace object
-        Ok(Trace::new(
-            Arc::try_unwrap(buffers)
-                .expect("failed to unwrap buffers from Arc<>")
-                .into_inner()
-                .expect("failed to get data out of rwlock"),
-            elapsed
-        ))
-    }
-}
-
-impl Drop for Blktrace {
-    fn drop(&mut self) {
-        use self::api::stop;
-        use self::api::teardown;
-        stop(self.blktrace_fd);
-        teardown(self.blktrace_fd);
+        Ok(Recording {
+            sinks: sinks,
+            start_time: start_time,
+            elapsed: elapsed,
+            dropped: dropped,
+            flush_start: flush_start,
+            thread_ids: seen_thread_ids.into_iter().collect(),
+        })
     }
 }
@@ -0,0 +1,95 @@
+// Decodes the payloads (PDUs) that some trace events carry after the fixed-size header.
+// Multi-byte fields are stored big-endian by the kernel.
+use super::trace::{Action, Category, Event};
+use std::collections::HashMap;
+
+// blktrace notify events reuse the action field for the notify type
+const NOTIFY_PROCESS: u32 = 0;
+const NOTIFY_MESSAGE: u32 = 2;
+
+// Driver data (DrvData events) has a driver-specific layout and is left undecoded
+pub enum Payload {
+    None,
+    // blk_io_trace_remap: the request now on this event's device and sector came from
+    // `device`/`sector`, e.g. from a partition or device-mapper target stacked on top
+    Remap { device: u32, sector: u64 },
+    // The bio was split; the second half starts at `sector`
+    Split { sector: u64 },
+    // Number of requests on the queue when it was unplugged
+    Unplug { depth: u64 },
+    // Name of the process with the event's pid, emitted the first time blktrace sees it
+    Process(String),
+    // Text added with blk_add_trace_msg
+    Message(String),
+}
+
+fn notify_kind(event: &Event) -> u32 {
+    match event.action {
+        Action::Other => NOTIFY_PROCESS,
+        Action::Backmerge => NOTIFY_MESSAGE,
+        _ => !0,
+    }
+}
+
+fn be64(data: &[u8], offset: usize) -> Option<u64> {
+    if data.len() < offset + 8 {
+        None
+    } else {
+        Some(data[offset..offset + 8].iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    if data.len() < offset + 4 {
+        None
+    } else {
+        Some(data[offset..offset + 4].iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+    }
+}
+
+// NUL-terminated string
+fn string(data: &[u8]) -> String {
+    let length = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..length]).into_owned()
+}
+
+impl Event {
+    pub fn payload(&self) -> Payload {
+        let data: &[u8] = match self.pdu {
+            Some(ref pdu) => &pdu.data,
+            None => &[],
+        };
+        if self.category.contains(Category::NOTIFY) {
+            return match notify_kind(self) {
+                NOTIFY_PROCESS => Payload::Process(string(data)),
+                NOTIFY_MESSAGE => Payload::Message(string(data)),
+                _ => Payload::None,
+            };
+        }
+        let decoded = match self.action {
+            // blk_io_trace_remap: __be32 device_from, __be32 device_to, __be64 sector_from
+            Action::Remap => match (be32(data, 0), be64(data, 8)) {
+                (Some(device), Some(sector)) => Some(Payload::Remap {
+                    device: device,
+                    sector: sector,
+                }),
+                _ => None,
+            },
+            Action::Split => be64(data, 0).map(|sector| Payload::Split { sector: sector }),
+            Action::UnplugIO | Action::UnplugTimer => be64(data, 0).map(|depth| Payload::Unplug { depth: depth }),
+            _ => None,
+        };
+        decoded.unwrap_or(Payload::None)
+    }
+}
+
+// Process names by pid, from the notify events in `events`
+pub fn process_names(events: &[Event]) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    for event in events.iter().filter(|event| event.category.contains(Category::NOTIFY)) {
+        if let Payload::Process(name) = event.payload() {
+            names.insert(event.pid, name);
+        }
+    }
+    names
+}
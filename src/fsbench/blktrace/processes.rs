@@ -0,0 +1,90 @@
+// Attributes block IO to the processes that submitted it.
+//
+// A bio is charged to the pid of its Queue event, i.e. the task that called submit_bio. IO
+// queued by the benchmark's own threads while the task runs is synchronous; everything else was
+// queued in the background, usually by kernel threads (kworker writeback, jbd2,
+// btrfs-transaction, f2fs_gc). The thread that ran the task also runs the sync and drop_caches
+// after it, and what those flush counts as background IO too.
+use super::pdu::process_names;
+use super::requests::Request;
+use super::trace::{Action, Category, Event, IoCount};
+use std::collections::HashMap;
+
+#[derive(Serialize)]
+pub struct ProcessIo {
+    pub pid: u32,
+    // From blktrace's notify events; empty if the process was never named
+    pub command: String,
+    // Whether the process is one of the benchmark's threads; what it queues outside the task is
+    // still asynchronous
+    pub benchmark: bool,
+    // Queued bios and their bytes
+    pub reads: IoCount,
+    pub writes: IoCount,
+    // Completed requests whose first bio this process queued
+    pub requests: usize,
+}
+
+#[derive(Serialize)]
+pub struct IoAttribution {
+    // Bios queued by the benchmark's threads while the task ran
+    pub synchronous: IoCount,
+    // Bios queued by any other process
+    pub asynchronous: IoCount,
+    // Sorted by bytes queued, largest first
+    pub processes: Vec<ProcessIo>,
+}
+
+// `benchmark_pids` are the thread ids of the benchmark process, and `task` the start and end
+// time (ns) of the task they ran
+pub fn attribute(events: &[Event], requests: &[Request], benchmark_pids: &[u32], task: (u64, u64)) -> IoAttribution {
+    let names = process_names(events);
+    let mut processes: Vec<ProcessIo> = Vec::new();
+    // Position of each pid in `processes`
+    let mut indices: HashMap<u32, usize> = HashMap::new();
+    // Index of `pid` in `processes`, adding it if it isn't there yet
+    let mut index_of = |processes: &mut Vec<ProcessIo>, pid: u32| {
+        *indices.entry(pid).or_insert_with(|| {
+            processes.push(ProcessIo {
+                pid: pid,
+                command: names.get(&pid).cloned().unwrap_or_default(),
+                benchmark: benchmark_pids.contains(&pid),
+                reads: IoCount::default(),
+                writes: IoCount::default(),
+                requests: 0,
+            });
+            processes.len() - 1
+        })
+    };
+
+    let mut synchronous = IoCount::default();
+    let mut asynchronous = IoCount::default();
+    for event in events
+        .iter()
+        .filter(|event| event.action == Action::Queue && !event.category.contains(Category::NOTIFY))
+    {
+        let index = index_of(&mut processes, event.pid);
+        let process = &mut processes[index];
+        if event.category.contains(Category::WRITE) {
+            process.writes.add(event.bytes as usize);
+        } else {
+            process.reads.add(event.bytes as usize);
+        }
+        if process.benchmark && event.time >= task.0 && event.time < task.1 {
+            synchronous.add(event.bytes as usize);
+        } else {
+            asynchronous.add(event.bytes as usize);
+        }
+    }
+    for request in requests {
+        let index = index_of(&mut processes, request.pid);
+        processes[index].requests += 1;
+    }
+    processes.sort_by(|a, b| (b.reads.bytes + b.writes.bytes).cmp(&(a.reads.bytes + a.writes.bytes)));
+
+    IoAttribution {
+        synchronous: synchronous,
+        asynchronous: asynchronous,
+        processes: processes,
+    }
+}
@@ -0,0 +1,218 @@
+// Shape of the IO stream as the device sees it: how large issued requests are, how many bios
+// were merged into them, and how many requests the device has in flight over time.
+use super::trace::{Action, Category, Event};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Serialize)]
+pub struct SizeBucket {
+    // Request size in bytes, [lower, upper)
+    pub lower: u64,
+    pub upper: u64,
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+pub struct SizeDistribution {
+    pub count: usize,
+    pub total_bytes: usize,
+    pub mean: f64,
+    // Power-of-two buckets
+    pub histogram: Vec<SizeBucket>,
+}
+
+impl SizeDistribution {
+    fn from_sizes<I: Iterator<Item = u32>>(sizes: I) -> SizeDistribution {
+        let mut buckets: Vec<usize> = Vec::new();
+        let mut count = 0;
+        let mut total_bytes = 0;
+        for size in sizes {
+            let bucket = 31 - size.leading_zeros() as usize;
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, 0);
+            }
+            buckets[bucket] += 1;
+            count += 1;
+            total_bytes += size as usize;
+        }
+        SizeDistribution {
+            count: count,
+            total_bytes: total_bytes,
+            mean: if count > 0 { total_bytes as f64 / count as f64 } else { 0.0 },
+            histogram: buckets
+                .iter()
+                .enumerate()
+                .filter(|&(_, count)| *count > 0)
+                .map(|(bucket, count)| SizeBucket {
+                    lower: 1 << bucket,
+                    upper: 1 << (bucket + 1),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+// Sizes of the requests issued to the device, i.e. after merging
+#[derive(Serialize)]
+pub struct RequestSizes {
+    pub reads: SizeDistribution,
+    pub writes: SizeDistribution,
+}
+
+fn issued_with<'a>(events: &'a [Event], category: Category) -> impl Iterator<Item = u32> + 'a {
+    events
+        .iter()
+        .filter(move |event| event.action == Action::Issue && event.bytes > 0 && event.category.contains(category))
+        .map(|event| event.bytes)
+}
+
+pub fn request_sizes(events: &[Event]) -> RequestSizes {
+    RequestSizes {
+        reads: SizeDistribution::from_sizes(issued_with(events, Category::READ)),
+        writes: SizeDistribution::from_sizes(issued_with(events, Category::WRITE)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct Merges {
+    // Bios queued by the filesystem
+    pub queued: usize,
+    // Bios appended to / prepended to a pending request instead of becoming a request of their own
+    pub backmerges: usize,
+    pub frontmerges: usize,
+    pub merged_bytes: usize,
+    // Fraction of queued bios that were merged
+    pub merge_ratio: f64,
+}
+
+pub fn merges(events: &[Event]) -> Merges {
+    let mut m = Merges {
+        queued: 0,
+        backmerges: 0,
+        frontmerges: 0,
+        merged_bytes: 0,
+        merge_ratio: 0.0,
+    };
+    for event in events.iter().filter(|event| !event.category.contains(Category::NOTIFY)) {
+        match event.action {
+            Action::Queue => m.queued += 1,
+            Action::Backmerge => m.backmerges += 1,
+            Action::Frontmerge => m.frontmerges += 1,
+            _ => continue,
+        }
+        if event.action != Action::Queue {
+            m.merged_bytes += event.bytes as usize;
+        }
+    }
+    if m.queued > 0 {
+        m.merge_ratio = (m.backmerges + m.frontmerges) as f64 / m.queued as f64;
+    }
+    m
+}
+
+// Number of requests in flight (issued to the driver and not yet completed) summed over all
+// devices, after every Issue/Requeue/Complete event, as (time, depth)
+pub fn depth_changes(events: &[Event]) -> Vec<(u64, usize)> {
+    let mut in_flight: HashMap<u32, usize> = HashMap::new();
+    let mut total = 0;
+    let mut changes = Vec::new();
+    for event in events.iter().filter(|event| !event.category.contains(Category::NOTIFY)) {
+        let depth = in_flight.entry(event.device).or_insert(0);
+        match event.action {
+            Action::Issue => {
+                *depth += 1;
+                total += 1;
+            }
+            Action::Requeue | Action::Complete => {
+                // Requests issued before tracing started complete without a matching Issue
+                if *depth == 0 {
+                    continue;
+                }
+                *depth -= 1;
+                total -= 1;
+            }
+            _ => continue,
+        }
+        changes.push((event.time, total));
+    }
+    changes
+}
+
+#[derive(Serialize)]
+pub struct DepthBucket {
+    pub depth: usize,
+    pub time: Duration,
+}
+
+#[derive(Serialize)]
+pub struct QueueDepth {
+    pub max: usize,
+    // Time-weighted mean between the first and last Issue/Complete
+    pub mean: f64,
+    // Time spent at each depth
+    pub distribution: Vec<DepthBucket>,
+}
+
+impl QueueDepth {
+    pub fn new(changes: &[(u64, usize)]) -> QueueDepth {
+        let mut time_at_depth: Vec<u64> = Vec::new();
+        for pair in changes.windows(2) {
+            let (from, depth) = pair[0];
+            if time_at_depth.len() <= depth {
+                time_at_depth.resize(depth + 1, 0);
+            }
+            time_at_depth[depth] += pair[1].0 - from;
+        }
+        let total_time = time_at_depth.iter().fold(0, |acc, t| acc + t);
+        let weighted = time_at_depth
+            .iter()
+            .enumerate()
+            .fold(0.0, |acc, (depth, t)| acc + depth as f64 * *t as f64);
+        QueueDepth {
+            max: changes.iter().map(|&(_, depth)| depth).max().unwrap_or(0),
+            mean: if total_time > 0 { weighted / total_time as f64 } else { 0.0 },
+            distribution: time_at_depth
+                .iter()
+                .enumerate()
+                .filter(|&(_, t)| *t > 0)
+                .map(|(depth, t)| DepthBucket {
+                    depth: depth,
+                    time: Duration::from_nanos(*t),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Time-weighted mean and max depth per fixed-size window starting at `origin`
+pub fn depth_windows(changes: &[(u64, usize)], origin: u64, window: Duration, num_windows: usize) -> Vec<(f64, usize)> {
+    let mut windows = vec![(0.0, 0); num_windows];
+    let window_nanos = window.as_secs() * 1_000_000_000 + window.subsec_nanos() as u64;
+    for pair in changes.windows(2) {
+        let (from, depth) = pair[0];
+        let to = pair[1].0;
+        if depth == 0 {
+            continue;
+        }
+        let mut time = from.max(origin);
+        while time < to {
+            let index = ((time - origin) / window_nanos) as usize;
+            if index >= num_windows {
+                break;
+            }
+            let until = to.min(origin + (index as u64 + 1) * window_nanos);
+            windows[index].0 += depth as f64 * (until - time) as f64 / window_nanos as f64;
+            windows[index].1 = windows[index].1.max(depth);
+            time = until;
+        }
+    }
+    windows
+}
+
+#[derive(Serialize)]
+pub struct QueueStats {
+    pub request_sizes: RequestSizes,
+    pub merges: Merges,
+    pub queue_depth: QueueDepth,
+}
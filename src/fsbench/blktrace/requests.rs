@@ -0,0 +1,406 @@
+// Reassembles block-layer requests from individual trace events, like `btt` does.
+//
+// Events carry no request identifier (`sequence` is per event), so a request is identified by
+// its device, start sector and size. A Queue event starts a request, GetRQ/Insert/Issue fill in
+// its phases and Complete finishes it. Bios that are back- or front-merged into a pending request
+// grow that request, so its later Issue/Complete events are matched under the new extent; a split
+// bio shrinks to its first part.
+use super::super::statistics::{LatencySummary, Stats};
+use super::pdu::Payload;
+use super::trace::{Action, Category, Event, SECTOR_SIZE};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub struct Request {
+    pub device: u32,
+    pub sector: u64,
+    pub bytes: u32,
+    // Task that queued the request's first bio (or issued it, for requests that weren't queued)
+    pub pid: u32,
+    // Event times (ns) of each phase, if the phase was seen
+    pub queue: Option<u64>,
+    pub getrq: Option<u64>,
+    pub insert: Option<u64>,
+    pub issue: Option<u64>,
+    pub complete: Option<u64>,
+}
+
+impl Request {
+    fn from_event(event: &Event) -> Request {
+        Request {
+            device: event.device,
+            sector: event.sector,
+            bytes: event.bytes,
+            pid: event.pid,
+            queue: None,
+            getrq: None,
+            insert: None,
+            issue: None,
+            complete: None,
+        }
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        self.device == event.device && self.sector == event.sector && self.bytes == event.bytes
+    }
+
+    fn end_sector(&self) -> u64 {
+        self.sector + (self.bytes as usize / SECTOR_SIZE) as u64
+    }
+
+    // Time between two phases, if both were seen
+    pub fn phase(from: Option<u64>, to: Option<u64>) -> Option<Duration> {
+        match (from, to) {
+            (Some(from), Some(to)) if to >= from => Some(Duration::from_nanos(to - from)),
+            _ => None,
+        }
+    }
+
+    pub fn q2g(&self) -> Option<Duration> {
+        Request::phase(self.queue, self.getrq)
+    }
+
+    pub fn g2i(&self) -> Option<Duration> {
+        Request::phase(self.getrq, self.insert)
+    }
+
+    pub fn i2d(&self) -> Option<Duration> {
+        Request::phase(self.insert, self.issue)
+    }
+
+    pub fn d2c(&self) -> Option<Duration> {
+        Request::phase(self.issue, self.complete)
+    }
+
+    pub fn q2c(&self) -> Option<Duration> {
+        Request::phase(self.queue, self.complete)
+    }
+}
+
+pub struct Requests {
+    // Requests that reached Complete, in completion order
+    pub completed: Vec<Request>,
+}
+
+// Requests that haven't completed yet, by device and start sector so that matching an event
+// doesn't scan every request still in flight, or every queued bio that never will be
+struct Pending {
+    // Oldest first for each key
+    requests: BTreeMap<(u32, u64), Vec<Request>>,
+    // Longest request seen (sectors), bounds the search for a request ending at a given sector
+    longest: u64,
+}
+
+impl Pending {
+    fn new() -> Pending {
+        Pending {
+            requests: BTreeMap::new(),
+            longest: 0,
+        }
+    }
+
+    fn push(&mut self, request: Request) {
+        self.longest = self.longest.max(request.end_sector() - request.sector);
+        self.requests
+            .entry((request.device, request.sector))
+            .or_insert_with(Vec::new)
+            .push(request);
+    }
+
+    // Index of the oldest request at `key` for which `filter` holds
+    fn position<F: Fn(&Request) -> bool>(&self, key: (u32, u64), filter: F) -> Option<usize> {
+        self.requests.get(&key).and_then(|requests| requests.iter().position(|r| filter(r)))
+    }
+
+    // Oldest request matching the event's extent for which `phase` holds, e.g. that hasn't
+    // reached a phase yet
+    fn find<F: Fn(&Request) -> bool>(&self, event: &Event, phase: F) -> Option<usize> {
+        self.position((event.device, event.sector), |r| r.matches(event) && phase(r))
+    }
+
+    // Newest request matching the event's extent for which `phase` holds
+    fn rfind<F: Fn(&Request) -> bool>(&self, event: &Event, phase: F) -> Option<usize> {
+        self.requests
+            .get(&(event.device, event.sector))
+            .and_then(|requests| requests.iter().rposition(|r| r.matches(event) && phase(r)))
+    }
+
+    // Request on `device` that ends at `sector` and hasn't been issued yet
+    fn ending_at(&self, device: u32, sector: u64) -> Option<((u32, u64), usize)> {
+        self.requests
+            .range((device, sector.saturating_sub(self.longest))..(device, sector))
+            .filter_map(|(key, requests)| {
+                requests
+                    .iter()
+                    .position(|r| r.issue.is_none() && r.end_sector() == sector)
+                    .map(|index| (*key, index))
+            })
+            .next()
+    }
+
+    fn get_mut(&mut self, key: (u32, u64), index: usize) -> &mut Request {
+        &mut self.requests.get_mut(&key).expect("no pending requests at key")[index]
+    }
+
+    fn remove(&mut self, key: (u32, u64), index: usize) -> Request {
+        let (request, now_empty) = {
+            let requests = self.requests.get_mut(&key).expect("no pending requests at key");
+            let request = requests.remove(index);
+            (request, requests.is_empty())
+        };
+        if now_empty {
+            self.requests.remove(&key);
+        }
+        request
+    }
+}
+
+// `events` must be sorted by time
+pub fn match_requests(events: &[Event]) -> Requests {
+    let mut pending = Pending::new();
+    let mut completed = Vec::new();
+
+    for event in events.iter().filter(|event| !event.category.contains(Category::NOTIFY)) {
+        let key = (event.device, event.sector);
+        match event.action {
+            Action::Queue => {
+                let mut request = Request::from_event(event);
+                request.queue = Some(event.time);
+                pending.push(request);
+            }
+            Action::Backmerge | Action::Frontmerge => {
+                // The merged bio was queued on its own first; it no longer becomes a request
+                if let Some(index) = pending.rfind(event, |r| r.getrq.is_none() && r.issue.is_none()) {
+                    pending.remove(key, index);
+                }
+                let sectors = (event.bytes as usize / SECTOR_SIZE) as u64;
+                let target = if event.action == Action::Backmerge {
+                    pending.ending_at(event.device, event.sector)
+                } else {
+                    let next = (event.device, event.sector + sectors);
+                    pending.position(next, |r| r.issue.is_none()).map(|index| (next, index))
+                };
+                if let Some((target_key, index)) = target {
+                    // Taken out and put back since a front merge moves the request's start
+                    let mut request = pending.remove(target_key, index);
+                    if event.action == Action::Frontmerge {
+                        request.sector = event.sector;
+                    }
+                    request.bytes += event.bytes;
+                    pending.push(request);
+                }
+            }
+            Action::Split => {
+                // The bio keeps its first part; the rest, starting at the payload's sector, is
+                // submitted again and shows up as a Queue event of its own
+                if let Payload::Split { sector } = event.payload() {
+                    let index = pending.position(key, |r| r.getrq.is_none() && r.issue.is_none() && r.end_sector() > sector);
+                    if let (Some(index), true) = (index, sector > event.sector) {
+                        pending.get_mut(key, index).bytes = ((sector - event.sector) as usize * SECTOR_SIZE) as u32;
+                    }
+                }
+            }
+            Action::GetRQ => {
+                if let Some(index) = pending.find(event, |r| r.getrq.is_none() && r.issue.is_none()) {
+                    pending.get_mut(key, index).getrq = Some(event.time);
+                }
+            }
+            Action::Insert => {
+                if let Some(index) = pending.find(event, |r| r.insert.is_none() && r.issue.is_none()) {
+                    pending.get_mut(key, index).insert = Some(event.time);
+                }
+            }
+            Action::Issue => match pending.find(event, |r| r.issue.is_none()) {
+                Some(index) => pending.get_mut(key, index).issue = Some(event.time),
+                None => {
+                    // Requests that were never queued as bios, e.g. flushes or requests queued
+                    // before tracing started
+                    let mut request = Request::from_event(event);
+                    request.issue = Some(event.time);
+                    pending.push(request);
+                }
+            },
+            Action::Requeue => {
+                if let Some(index) = pending.find(event, |r| r.issue.is_some()) {
+                    pending.get_mut(key, index).issue = None;
+                }
+            }
+            Action::Complete => {
+                let index = pending.find(event, |r| r.issue.is_some()).or_else(|| pending.find(event, |_| true));
+                if let Some(index) = index {
+                    let mut request = pending.remove(key, index);
+                    request.complete = Some(event.time);
+                    completed.push(request);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Requests {
+        completed: completed,
+    }
+}
+
+#[derive(Serialize)]
+pub struct PhaseLatency {
+    pub count: usize,
+    pub total: Duration,
+    pub latency: LatencySummary,
+}
+
+impl PhaseLatency {
+    fn from_durations<I: Iterator<Item = Duration>>(durations: I) -> PhaseLatency {
+        let mut stats = Stats::raw();
+        for duration in durations {
+            stats.record(0, duration, 0, 0, None);
+        }
+        PhaseLatency {
+            count: stats.num_ops(),
+            total: stats.total_latency(),
+            latency: stats.latency_summary(),
+        }
+    }
+}
+
+// Per-phase latency distributions, named as in btt:
+// Q2G = queued -> request allocated, G2I = allocated -> inserted into the scheduler,
+// I2D = inserted -> issued to the driver, D2C = issued -> completed (device time),
+// Q2C = queued -> completed (total)
+#[derive(Serialize)]
+pub struct LatencyBreakdown {
+    pub q2g: PhaseLatency,
+    pub g2i: PhaseLatency,
+    pub i2d: PhaseLatency,
+    pub d2c: PhaseLatency,
+    pub q2c: PhaseLatency,
+}
+
+impl LatencyBreakdown {
+    pub fn new(requests: &[Request]) -> LatencyBreakdown {
+        LatencyBreakdown {
+            q2g: PhaseLatency::from_durations(requests.iter().filter_map(|r| r.q2g())),
+            g2i: PhaseLatency::from_durations(requests.iter().filter_map(|r| r.g2i())),
+            i2d: PhaseLatency::from_durations(requests.iter().filter_map(|r| r.i2d())),
+            d2c: PhaseLatency::from_durations(requests.iter().filter_map(|r| r.d2c())),
+            q2c: PhaseLatency::from_durations(requests.iter().filter_map(|r| r.q2c())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::trace::{Action, Category, Event, EventPDU};
+    use super::match_requests;
+
+    fn event(time: u64, action: Action, sector: u64, bytes: u32) -> Event {
+        Event {
+            sequence: 0,
+            time: time,
+            sector: sector,
+            bytes: bytes,
+            action: action,
+            category: Category::WRITE,
+            pid: 100,
+            device: 8 << 20,
+            cpu: 0,
+            error: 0,
+            pdu: None,
+        }
+    }
+
+    // X event splitting the bio at `sector`; the PDU is the big-endian start of the second part
+    fn split(time: u64, sector: u64, bytes: u32, at: u64) -> Event {
+        let mut event = event(time, Action::Split, sector, bytes);
+        event.pdu = Some(EventPDU {
+            data: (0..8).rev().map(|shift| (at >> (shift * 8)) as u8).collect(),
+        });
+        event
+    }
+
+    #[test]
+    fn matches_every_phase() {
+        let events = vec![
+            event(10, Action::Queue, 0, 4096),
+            event(20, Action::GetRQ, 0, 4096),
+            event(30, Action::Insert, 0, 4096),
+            event(40, Action::Issue, 0, 4096),
+            event(100, Action::Complete, 0, 4096),
+        ];
+        let completed = match_requests(&events).completed;
+        assert_eq!(completed.len(), 1);
+        let request = &completed[0];
+        assert_eq!((request.queue, request.getrq, request.insert), (Some(10), Some(20), Some(30)));
+        assert_eq!((request.issue, request.complete), (Some(40), Some(100)));
+        assert_eq!(request.q2c().map(|d| d.subsec_nanos()), Some(90));
+        assert_eq!(request.d2c().map(|d| d.subsec_nanos()), Some(60));
+    }
+
+    #[test]
+    fn merged_bios_grow_the_request() {
+        let events = vec![
+            event(10, Action::Queue, 8, 4096),
+            event(11, Action::GetRQ, 8, 4096),
+            // Back merge of sectors 16..24 and front merge of 0..8
+            event(12, Action::Queue, 16, 4096),
+            event(13, Action::Backmerge, 16, 4096),
+            event(14, Action::Queue, 0, 4096),
+            event(15, Action::Frontmerge, 0, 4096),
+            event(20, Action::Issue, 0, 12288),
+            event(30, Action::Complete, 0, 12288),
+        ];
+        let completed = match_requests(&events).completed;
+        assert_eq!(completed.len(), 1);
+        assert_eq!((completed[0].sector, completed[0].bytes), (0, 12288));
+        assert_eq!(completed[0].queue, Some(10));
+    }
+
+    #[test]
+    fn split_bios_complete_in_parts() {
+        let events = vec![
+            event(10, Action::Queue, 0, 1 << 20),
+            split(11, 0, 1 << 19, 1024),
+            event(12, Action::Queue, 1024, 1 << 19),
+            event(13, Action::GetRQ, 0, 1 << 19),
+            event(14, Action::GetRQ, 1024, 1 << 19),
+            event(20, Action::Issue, 0, 1 << 19),
+            event(21, Action::Issue, 1024, 1 << 19),
+            event(30, Action::Complete, 0, 1 << 19),
+            event(31, Action::Complete, 1024, 1 << 19),
+        ];
+        let completed = match_requests(&events).completed;
+        assert_eq!(completed.len(), 2);
+        assert_eq!((completed[0].sector, completed[0].queue), (0, Some(10)));
+        assert_eq!((completed[1].sector, completed[1].queue), (1024, Some(12)));
+    }
+
+    #[test]
+    fn unqueued_and_unmatched_events() {
+        let events = vec![
+            // Queued but never seen again, e.g. filtered out by the trace mask
+            event(5, Action::Queue, 64, 4096),
+            // A flush is issued without being queued as a bio
+            event(10, Action::Issue, 0, 0),
+            event(20, Action::Complete, 0, 0),
+            // Completion of a request queued before tracing started
+            event(30, Action::Complete, 128, 4096),
+        ];
+        let completed = match_requests(&events).completed;
+        assert_eq!(completed.len(), 1);
+        assert_eq!((completed[0].issue, completed[0].queue), (Some(10), None));
+    }
+
+    #[test]
+    fn requeued_request_is_issued_again() {
+        let events = vec![
+            event(10, Action::Queue, 0, 4096),
+            event(20, Action::Issue, 0, 4096),
+            event(25, Action::Requeue, 0, 4096),
+            event(30, Action::Issue, 0, 4096),
+            event(40, Action::Complete, 0, 4096),
+        ];
+        let completed = match_requests(&events).completed;
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].issue, Some(30));
+    }
+}
@@ -0,0 +1,141 @@
+// Where on the device IO lands: how sequential the issued requests are, how far the device has
+// to seek between them, and which LBA regions are written over time.
+use super::trace::{Action, Event, SECTOR_SIZE};
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+#[derive(Serialize)]
+pub struct SeekBucket {
+    // Distance in sectors, [lower, upper)
+    pub lower: u64,
+    pub upper: u64,
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+pub struct SpatialStats {
+    // Issued requests that start exactly where the previous request on the same device ended
+    pub sequential: usize,
+    pub random: usize,
+    pub sequential_ratio: f64,
+    // Random requests that start before / after the end of the previous request
+    pub backward_seeks: usize,
+    pub forward_seeks: usize,
+    // Seek distances between consecutive issued requests, power-of-two buckets
+    pub seek_histogram: Vec<SeekBucket>,
+}
+
+fn end_sector(event: &Event) -> u64 {
+    event.sector + (event.bytes as usize / SECTOR_SIZE) as u64
+}
+
+// Issued requests that touch the disk surface (i.e. not empty flushes)
+fn issued<'a>(events: &'a [Event]) -> impl Iterator<Item = &'a Event> + 'a {
+    events.iter().filter(|event| event.action == Action::Issue && event.bytes > 0)
+}
+
+// `events` must be sorted by time
+pub fn spatial_stats(events: &[Event]) -> SpatialStats {
+    let mut previous_end: HashMap<u32, u64> = HashMap::new();
+    let mut sequential = 0;
+    let mut random = 0;
+    let mut backward_seeks = 0;
+    let mut forward_seeks = 0;
+    let mut buckets: Vec<usize> = Vec::new();
+    for event in issued(events) {
+        if let Some(&end) = previous_end.get(&event.device) {
+            let distance = if event.sector >= end {
+                event.sector - end
+            } else {
+                end - event.sector
+            };
+            if distance == 0 {
+                sequential += 1;
+            } else {
+                random += 1;
+                if event.sector < end {
+                    backward_seeks += 1;
+                } else {
+                    forward_seeks += 1;
+                }
+            }
+            // bucket 0 holds sequential requests, bucket i > 0 holds distances in [2^(i-1), 2^i)
+            let bucket = 64 - distance.leading_zeros() as usize;
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, 0);
+            }
+            buckets[bucket] += 1;
+        }
+        previous_end.insert(event.device, end_sector(event));
+    }
+    let total = sequential + random;
+    SpatialStats {
+        sequential: sequential,
+        random: random,
+        sequential_ratio: if total > 0 { sequential as f64 / total as f64 } else { 0.0 },
+        backward_seeks: backward_seeks,
+        forward_seeks: forward_seeks,
+        seek_histogram: buckets
+            .iter()
+            .enumerate()
+            .filter(|&(_, count)| *count > 0)
+            .map(|(bucket, count)| SeekBucket {
+                lower: if bucket == 0 { 0 } else { 1 << (bucket - 1) },
+                upper: if bucket == 0 { 1 } else if bucket >= 64 { ::std::u64::MAX } else { 1 << bucket },
+                count: *count,
+            })
+            .collect(),
+    }
+}
+
+// Bytes issued per (time window, LBA region). Regions split [0, highest sector touched) evenly.
+pub struct Heatmap {
+    pub region_sectors: u64,
+    pub window: Duration,
+    // bytes[window][region]
+    pub bytes: Vec<Vec<usize>>,
+}
+
+// `start` is the time (ns) of the first window
+pub fn lba_heatmap(events: &[Event], start: u64, num_regions: usize, window: Duration) -> Heatmap {
+    let max_sector = issued(events).map(end_sector).max().unwrap_or(0);
+    let region_sectors = ((max_sector + num_regions as u64 - 1) / num_regions as u64).max(1);
+    let window_nanos = (window.as_secs() * 1_000_000_000 + window.subsec_nanos() as u64).max(1);
+    let mut bytes: Vec<Vec<usize>> = Vec::new();
+    for event in issued(events).filter(|event| event.time >= start) {
+        let index = ((event.time - start) / window_nanos) as usize;
+        while bytes.len() <= index {
+            bytes.push(vec![0; num_regions]);
+        }
+        let region = ((event.sector / region_sectors) as usize).min(num_regions - 1);
+        bytes[index][region] += event.bytes as usize;
+    }
+    Heatmap {
+        region_sectors: region_sectors,
+        window: window,
+        bytes: bytes,
+    }
+}
+
+impl Heatmap {
+    // One row per time window, one column per LBA region (labelled with its first sector)
+    pub fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let num_regions = self.bytes.first().map(|row| row.len()).unwrap_or(0);
+        write!(out, "time_ms")?;
+        for region in 0..num_regions {
+            write!(out, ",{}", region as u64 * self.region_sectors)?;
+        }
+        writeln!(out)?;
+        let window_ms = self.window.as_secs() * 1000 + (self.window.subsec_nanos() / 1_000_000) as u64;
+        for (index, row) in self.bytes.iter().enumerate() {
+            write!(out, "{}", index as u64 * window_ms)?;
+            for bytes in row {
+                write!(out, ",{}", bytes)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,28 @@
 use super::api::BlkIOTrace;
+use super::blkparse::{major, minor, write_events};
+use super::pdu::Payload;
+use super::processes::{attribute, IoAttribution};
+use super::queue::{depth_changes, depth_windows, merges, request_sizes, QueueDepth, QueueStats};
+use super::requests::{match_requests, LatencyBreakdown, Request, Requests};
+use super::spatial::{lba_heatmap, spatial_stats, Heatmap, SpatialStats};
+use super::super::super::serde_json;
+use super::super::diskstats::{DeviceStats, DiskStatsCheck};
+use super::super::ftrace::FsEvents;
+use super::super::statistics::duration_to_nanos;
 use std::cmp::Ordering;
+use std::fs;
 use std::io;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
-use std::collections::*;
 
 
 pub const SECTOR_SIZE: usize = 512;
 
+// Resolution of the exported LBA heatmap
+const HEATMAP_REGIONS: usize = 64;
+const HEATMAP_WINDOW_MS: u64 = 100;
+
 #[derive(FromPrimitive, PartialEq, Eq, Debug)]
 pub enum Action {
     Other = 0,
@@ -117,100 +132,562 @@ impl PartialOrd for Event {
     }
 }
 
-unsafe fn as_trace<'a>(b: &'a [u8]) -> &'a BlkIOTrace {
+// Records are packed back to back with variable-length payloads, so a header can be at any alignment
+fn read_trace(b: &[u8]) -> BlkIOTrace {
     use std::mem;
+    use std::ptr;
     assert!(b.len() == mem::size_of::<BlkIOTrace>());
-    let s: *const BlkIOTrace = b.as_ptr() as *const _;
-    s.as_ref().unwrap()
+    unsafe { ptr::read_unaligned(b.as_ptr() as *const BlkIOTrace) }
+}
+
+// Fills `buffer` from `reader` unless it ends first; returns how many bytes were read
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(length) => filled += length,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Every record starts with BLK_IO_TRACE_MAGIC; the low byte holds the format version
+const MAGIC: u32 = 0x6561_7400;
+const MAGIC_MASK: u32 = 0xffff_ff00;
+
+// The device and CPU whose events a relay buffer or trace file holds
+#[derive(Clone)]
+pub struct TraceSource {
+    // Kernel name, e.g. "sda1" or "dm-0"
+    pub device: String,
+    pub cpu: u32,
+}
+
+impl TraceSource {
+    // "<prefix>.<device>.blktrace.<cpu>", which `load` and `find_prefixes` read back
+    pub fn file_name(&self, prefix: &str) -> String {
+        format!("{}.{}.blktrace.{}", prefix, self.device, self.cpu)
+    }
+}
+
+// Events missing from one per-CPU buffer
+#[derive(Clone, Serialize)]
+pub struct CpuLoss {
+    pub device: String,
+    pub cpu: u32,
+    // Sum of the gaps between consecutive sequence numbers
+    pub missing_events: usize,
+    // A record with a bad magic was found; the rest of the buffer was skipped
+    pub corrupt: bool,
+}
+
+// Events the trace is known to be missing
+#[derive(Clone, Default, Serialize)]
+pub struct TraceLoss {
+    // Events the kernel dropped because all relay sub-buffers were full (debugfs `dropped`)
+    pub dropped: usize,
+    // Only CPUs that lost events
+    pub cpus: Vec<CpuLoss>,
+}
+
+impl TraceLoss {
+    // Dropped events also show up as sequence gaps, so the two counts overlap
+    pub fn missing_events(&self) -> usize {
+        let gaps = self.cpus.iter().fold(0, |acc, cpu| acc + cpu.missing_events);
+        if gaps > self.dropped {
+            gaps
+        } else {
+            self.dropped
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing_events() == 0 && !self.cpus.iter().any(|cpu| cpu.corrupt)
+    }
 }
 
-fn parse(b: &[u8]) -> Vec<Event> {
+// Parses one per-CPU buffer record by record, so a trace file never has to be read into memory
+// as a whole; only the decoded events are kept
+fn parse<R: Read>(mut reader: R, source: &TraceSource) -> io::Result<(Vec<Event>, Option<CpuLoss>)> {
     use std::mem;
     const STEP_SIZE: usize = mem::size_of::<BlkIOTrace>();
-    let mut index: usize = 0;
-    let mut events = Vec::new();
-    while index + STEP_SIZE < b.len() {
-        let trace = unsafe { as_trace(&b[index..index + STEP_SIZE]) };
-        index += STEP_SIZE;
-        let pdu_data = &b[index..index + trace.pdu_len as usize];
-        let event = Event::from_raw(trace, pdu_data);
+    let mut header = [0u8; STEP_SIZE];
+    let mut pdu_data = Vec::new();
+    let mut events: Vec<Event> = Vec::new();
+    let mut missing_events = 0;
+    let mut corrupt = false;
+    // Sequence numbers are per CPU and count every event except notify events
+    let mut last_sequence: Option<u32> = None;
+    while read_full(&mut reader, &mut header)? == STEP_SIZE {
+        let trace = read_trace(&header);
+        if trace.magic & MAGIC_MASK != MAGIC {
+            // Without a valid header there is no way to find the next record
+            corrupt = true;
+            break;
+        }
+        pdu_data.resize(trace.pdu_len as usize, 0);
+        if read_full(&mut reader, &mut pdu_data)? < pdu_data.len() {
+            // truncated event at the end of the buffer
+            break;
+        }
+        let event = Event::from_raw(&trace, &pdu_data);
+        if !event.category.contains(Category::NOTIFY) {
+            if let Some(last) = last_sequence {
+                if event.sequence > last + 1 {
+                    missing_events += (event.sequence - last - 1) as usize;
+                }
+            }
+            last_sequence = Some(event.sequence);
+        }
         events.push(event);
-        // NOTE: we do not check if the trace is valid
-        index += trace.pdu_len as usize;
     }
-    events
+    let loss = if missing_events > 0 || corrupt {
+        Some(CpuLoss {
+            device: source.device.clone(),
+            cpu: source.cpu,
+            missing_events: missing_events,
+            corrupt: corrupt,
+        })
+    } else {
+        None
+    };
+    Ok((events, loss))
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct IoCount {
+    pub requests: usize,
+    pub bytes: usize,
+}
+
+impl IoCount {
+    pub fn add(&mut self, bytes: usize) {
+        self.requests += 1;
+        self.bytes += bytes;
+    }
+}
+
+// Completed requests and bytes per kind of IO. Every completed request is counted in exactly one
+// of the first eight buckets; `sync_writes` additionally counts writes flagged SYNC.
+// Journal writes are identified the way filesystems issue journal commits: with FUA and/or a
+// preceding cache flush (jbd2 commit blocks, XFS log writes, f2fs checkpoints).
+#[derive(Clone, Default, Serialize)]
+pub struct IoCategories {
+    pub data_reads: IoCount,
+    pub metadata_reads: IoCount,
+    pub readahead: IoCount,
+    pub data_writes: IoCount,
+    pub metadata_writes: IoCount,
+    pub journal_writes: IoCount,
+    // Cache flushes that carry no data
+    pub flushes: IoCount,
+    pub discards: IoCount,
+    pub sync_writes: IoCount,
+}
+
+// Completed IO in `events` split by the category flags the kernel attached to each request
+fn categorize<'a, I: Iterator<Item = &'a Event>>(events: I) -> IoCategories {
+    let mut c = IoCategories::default();
+    for event in events.filter(|event| event.action == Action::Complete) {
+        let category = event.category;
+        let bucket = if category.contains(Category::DISCARD) {
+            &mut c.discards
+        } else if category.contains(Category::FLUSH) && event.bytes == 0 {
+            &mut c.flushes
+        } else if category.contains(Category::WRITE) {
+            if category.intersects(Category::FUA | Category::FLUSH) {
+                &mut c.journal_writes
+            } else if category.contains(Category::META) {
+                &mut c.metadata_writes
+            } else {
+                &mut c.data_writes
+            }
+        } else if category.contains(Category::READ) {
+            if category.contains(Category::AHEAD) {
+                &mut c.readahead
+            } else if category.contains(Category::META) {
+                &mut c.metadata_reads
+            } else {
+                &mut c.data_reads
+            }
+        } else {
+            continue;
+        };
+        bucket.add(event.bytes as usize);
+        if category.contains(Category::WRITE) && category.contains(Category::SYNC) {
+            c.sync_writes.add(event.bytes as usize);
+        }
+    }
+    c
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct PhaseIo {
+    pub reads: IoCount,
+    pub writes: IoCount,
+}
+
+// Completed IO by the part of the recording it completed in. Filesystems that delay allocation
+// or writeback do little IO while the task runs and catch up afterwards.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct IoPhases {
+    // Includes the little IO that completes between the warmup and the task start
+    pub during_task: PhaseIo,
+    // Background writeback during the cooldown
+    pub after_task: PhaseIo,
+    // IO forced out by the sync + drop_caches at the end, if it ran
+    pub flush: PhaseIo,
+}
+
+// Completed IO of one device
+#[derive(Serialize)]
+pub struct DeviceIo {
+    // "major,minor", as blkparse prints it
+    pub device: String,
+    pub reads: usize,
+    pub writes: usize,
+    pub io_requests: usize,
+    pub io_duration: Duration,
+    pub io_categories: IoCategories,
+    // IO that reached this device through a remap, by the device it was issued to
+    pub remapped_from: Vec<RemapSource>,
+}
+
+// IO remapped from a partition or stacked (LVM, dm-crypt, md) device
+#[derive(Serialize)]
+pub struct RemapSource {
+    pub device: String,
+    pub requests: usize,
+    pub bytes: usize,
+}
+
+// Splits "<prefix>.<device>.blktrace.<cpu>" (see TraceSource::file_name) into its prefix,
+// device and cpu number. Files written by blktrace itself are named "<device>.blktrace.<cpu>";
+// their device doubles as the prefix.
+fn split_trace_file_name(name: &str) -> Option<(&str, &str, u32)> {
+    const MARKER: &str = ".blktrace.";
+    let position = name.rfind(MARKER)?;
+    let cpu = name[position + MARKER.len()..].parse().ok()?;
+    let stem = &name[..position];
+    Some(match stem.rfind('.') {
+        Some(dot) => (&stem[..dot], &stem[dot + 1..], cpu),
+        None => (stem, stem, cpu),
+    })
+}
+
+// Where the raw per-CPU trace data lives
+enum TraceData {
+    Memory(Vec<Vec<u8>>),
+    // Per-CPU files, e.g. streamed to disk by Blktrace::record_to
+    Files(Vec<PathBuf>),
 }
 
 pub struct Trace {
-    data: Vec<Vec<u8>>,
+    data: TraceData,
+    // Device and CPU of each buffer or file in `data`
+    sources: Vec<TraceSource>,
     events: Vec<Event>,
+    // CLOCK_MONOTONIC time (ns) at which the traced task started; same clock as Event::time
+    start: u64,
     elapsed: Duration,
+    requests: Requests,
+    loss: TraceLoss,
+    // Thread ids of the process that ran the traced task
+    benchmark_pids: Vec<u32>,
+    // When the post-task sync + drop_caches started (ns), if it ran
+    flush_start: Option<u64>,
+    // Block layer counters of the traced devices over the same recording, if they were read
+    disk_stats: Vec<DeviceStats>,
+    // Whether blktrace only traced part of the IO, see BlktraceConfig::is_filtered
+    filtered: bool,
+    // Filesystem tracepoints over the same recording, if ftrace was enabled
+    fs_events: Option<FsEvents>,
 }
 
 impl Trace {
-    pub fn new(data: Vec<Vec<u8>>, elapsed: Duration) -> Self {
-        let mut events = data.iter().map(|d| parse(&d)).fold(Vec::new(), |mut acc, s| { acc.extend(s); acc });
+    pub fn new(sources: Vec<TraceSource>, data: Vec<Vec<u8>>, start: u64, elapsed: Duration) -> Self {
+        let mut events = Vec::new();
+        let mut loss = TraceLoss::default();
+        for (source, d) in sources.iter().zip(data.iter()) {
+            let (parsed, cpu_loss) = parse(&d[..], source).expect("reading from memory can't fail");
+            events.extend(parsed);
+            loss.cpus.extend(cpu_loss);
+        }
+        Trace::from_events(sources, TraceData::Memory(data), events, loss, start, elapsed)
+    }
+
+    // Parses per-CPU trace files one at a time and one record at a time. The raw data is never
+    // held in memory, but the decoded events are: memory use is still proportional to the
+    // number of events.
+    pub fn from_files(sources: Vec<TraceSource>, paths: Vec<PathBuf>, start: u64, elapsed: Duration) -> io::Result<Self> {
+        use std::fs::File;
+        use std::io::BufReader;
+        let mut events = Vec::new();
+        let mut loss = TraceLoss::default();
+        for (source, path) in sources.iter().zip(paths.iter()) {
+            let (parsed, cpu_loss) = parse(BufReader::new(File::open(path)?), source)?;
+            events.extend(parsed);
+            loss.cpus.extend(cpu_loss);
+        }
+        Ok(Trace::from_events(sources, TraceData::Files(paths), events, loss, start, elapsed))
+    }
+
+    fn from_events(
+        sources: Vec<TraceSource>,
+        data: TraceData,
+        mut events: Vec<Event>,
+        loss: TraceLoss,
+        start: u64,
+        elapsed: Duration,
+    ) -> Self {
         events.sort();
+        let requests = match_requests(&events);
         Self {
             data: data,
+            sources: sources,
             events: events,
+            requests: requests,
+            start: start,
             elapsed: elapsed,
+            loss: loss,
+            benchmark_pids: Vec::new(),
+            flush_start: None,
+            disk_stats: Vec::new(),
+            filtered: false,
+            fs_events: None,
+        }
+    }
+
+    // A recording made without blktrace: only the task's start and duration are known, plus
+    // whatever is added with set_disk_stats
+    pub fn untraced(start: u64, elapsed: Duration) -> Self {
+        Trace::new(Vec::new(), Vec::new(), start, elapsed)
+    }
+
+    // false if the trace was made with `untraced`, in which case it has no events
+    pub fn is_traced(&self) -> bool {
+        self.num_cpus() > 0
+    }
+
+    // Rebuilds a Trace from the per-device and per-CPU `<prefix>.<device>.blktrace.<cpu>` files
+    // written by `export`, or the `<device>.blktrace.<cpu>` files of the upstream blktrace tool.
+    // The files don't record when the traced task ran, so the trace is taken to span from its
+    // first event to its last.
+    pub fn load<P: AsRef<Path>>(path: P, prefix: &str) -> io::Result<Self> {
+        let mut files: Vec<(String, u32, PathBuf)> = fs::read_dir(path.as_ref())?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let file_name = entry.file_name();
+                match file_name.to_str().and_then(|name| split_trace_file_name(name)) {
+                    Some((p, device, cpu)) if p == prefix => Some((device.to_owned(), cpu, entry.path())),
+                    _ => None,
+                }
+            })
+            .collect();
+        if files.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no {}.<device>.blktrace.N or {}.blktrace.N files in {:?}", prefix, prefix, path.as_ref()),
+            ));
+        }
+        files.sort();
+        let sources = files
+            .iter()
+            .map(|&(ref device, cpu, _)| TraceSource {
+                device: device.clone(),
+                cpu: cpu,
+            })
+            .collect();
+        let paths = files.into_iter().map(|(_, _, file_path)| file_path).collect();
+        let mut trace = Trace::from_files(sources, paths, 0, Duration::new(0, 0))?;
+        if let (Some(first), Some(last)) = (trace.events.first().map(|e| e.time), trace.events.last().map(|e| e.time)) {
+            trace.start = first;
+            trace.elapsed = Duration::from_nanos(last - first);
+        }
+        Ok(trace)
+    }
+
+    // Prefixes of all `<prefix>.<device>.blktrace.<cpu>` files in a directory, e.g. "blktrace" for the
+    // files fsbench writes or the device name ("sda") for files written by blktrace itself
+    pub fn find_prefixes<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+        let mut prefixes: Vec<String> = fs::read_dir(path.as_ref())?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| split_trace_file_name(name))
+                    .map(|(prefix, _, _)| prefix.to_owned())
+            })
+            .collect();
+        prefixes.sort();
+        prefixes.dedup();
+        Ok(prefixes)
+    }
+
+    // Records the kernel's count of events dropped while this trace was recorded
+    pub fn set_dropped(&mut self, dropped: usize) {
+        self.loss.dropped = dropped;
+    }
+
+    pub fn set_flush_start(&mut self, flush_start: Option<u64>) {
+        self.flush_start = flush_start;
+    }
+
+    pub fn set_disk_stats(&mut self, disk_stats: Vec<DeviceStats>) {
+        self.disk_stats = disk_stats;
+    }
+
+    pub fn disk_stats(&self) -> &[DeviceStats] {
+        &self.disk_stats
+    }
+
+    pub fn set_filtered(&mut self, filtered: bool) {
+        self.filtered = filtered;
+    }
+
+    pub fn set_fs_events(&mut self, fs_events: FsEvents) {
+        self.fs_events = Some(fs_events);
+    }
+
+    pub fn fs_events(&self) -> Option<&FsEvents> {
+        self.fs_events.as_ref()
+    }
+
+    // Compares the completed IO in the trace with the block layer counters. None unless there
+    // are both, or if blktrace was filtered and so can't see all the IO the counters do.
+    pub fn disk_stats_check(&self) -> Option<DiskStatsCheck> {
+        if !self.is_traced() || self.disk_stats.is_empty() || self.filtered {
+            return None;
         }
+        Some(DiskStatsCheck::new(&self.disk_stats, self.completed_reads(), self.completed_writes()))
+    }
+
+    // Completed IO split by when it completed relative to the task and the post-task flush
+    pub fn io_phases(&self) -> IoPhases {
+        let task_end = self.start + duration_to_nanos(self.elapsed);
+        let mut phases = IoPhases::default();
+        for event in self.events.iter().filter(|event| event.action == Action::Complete) {
+            let phase = if event.time < task_end {
+                &mut phases.during_task
+            } else if self.flush_start.map_or(true, |flush_start| event.time < flush_start) {
+                &mut phases.after_task
+            } else {
+                &mut phases.flush
+            };
+            if event.category.contains(Category::WRITE) {
+                phase.writes.add(event.bytes as usize);
+            } else if event.category.contains(Category::READ) {
+                phase.reads.add(event.bytes as usize);
+            }
+        }
+        phases
+    }
+
+    // Marks IO these threads queue while the task runs as issued synchronously by the benchmark
+    pub fn set_benchmark_pids(&mut self, pids: Vec<u32>) {
+        self.benchmark_pids = pids;
+    }
+
+    // Queued IO per process, and how much of it the benchmark issued itself
+    pub fn io_by_process(&self) -> IoAttribution {
+        let task = (self.start, self.start + duration_to_nanos(self.elapsed));
+        attribute(&self.events, &self.requests.completed, &self.benchmark_pids, task)
+    }
+
+    pub fn loss(&self) -> &TraceLoss {
+        &self.loss
     }
 
     pub fn num_cpus(&self) -> usize {
-        self.data.len()
+        match self.data {
+            TraceData::Memory(ref buffers) => buffers.len(),
+            TraceData::Files(ref paths) => paths.len(),
+        }
     }
 
     pub fn total_bytes(&self) -> usize {
-        self.data.iter().fold(0, |acc, s| acc + s.len())
+        match self.data {
+            TraceData::Memory(ref buffers) => buffers.iter().fold(0, |acc, s| acc + s.len()),
+            TraceData::Files(ref paths) => paths
+                .iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .fold(0, |acc, metadata| acc + metadata.len() as usize),
+        }
     }
 
     pub fn export<P: AsRef<Path>, Q: AsRef<Path>>(&self, path: &P, prefix: &Q) -> io::Result<()> {
         use super::super::util::mkdir;
         use std::fs::File;
-        use std::io::Write;
-        use std::process::Command;
+        use std::io::{BufWriter, Write};
         mkdir(path.as_ref())?;
-        for (index, buf) in self.data.iter().enumerate() {
+        if !self.disk_stats.is_empty() {
             let mut filename = PathBuf::new();
             filename.set_file_name(prefix.as_ref());
-            filename.set_extension(format!("blktrace.{}", index));
-            let mut full_filename = PathBuf::new();
-            full_filename.push(path);
-            full_filename.push(filename);
-            match File::create(full_filename) {
-                Ok(mut file) => {
-                    file.write_all(buf)?;
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
+            filename.set_extension("diskstats.json");
+            serde_json::to_writer(File::create(path.as_ref().join(filename))?, &self.disk_stats)?;
         }
-        /*
-        let blkparse = Command::new("blkparse")
-            .args(&[path.as_ref().join(prefix).to_str().expect("failed to convert path to string")])
-            .output();
-        match blkparse {
-            Ok(blkparse_output) => {
-                let mut filename = PathBuf::new();
-                filename.set_file_name(prefix.as_ref());
-                filename.set_extension("txt");
-                let mut full_filename = PathBuf::new();
-                full_filename.push(path);
-                full_filename.push(filename);
-                match File::create(full_filename) {
-                    Ok(mut file) => file.write_all(&blkparse_output.stdout),
-                    Err(e) => Err(e),
+        if let Some(ref fs_events) = self.fs_events {
+            let mut filename = PathBuf::new();
+            filename.set_file_name(prefix.as_ref());
+            filename.set_extension("ftrace.json");
+            serde_json::to_writer(File::create(path.as_ref().join(filename))?, fs_events)?;
+        }
+        // Everything else is derived from the blktrace events
+        if !self.is_traced() {
+            return Ok(());
+        }
+        for (index, source) in self.sources.iter().enumerate() {
+            let full_filename = path.as_ref().join(source.file_name(&prefix.as_ref().to_string_lossy()));
+            match self.data {
+                TraceData::Memory(ref buffers) => {
+                    File::create(full_filename)?.write_all(&buffers[index])?;
+                }
+                TraceData::Files(ref paths) => {
+                    // Streamed traces are usually exported to where they were recorded
+                    let same_file = match (fs::canonicalize(&paths[index]), fs::canonicalize(&full_filename)) {
+                        (Ok(source), Ok(target)) => source == target,
+                        _ => false,
+                    };
+                    if !same_file {
+                        fs::copy(&paths[index], full_filename)?;
+                    }
                 }
-            }
-            Err(_) => {
-                // Ignore errors caused by running blkparse
-                Ok(())
             }
         }
-         */
+        // Also write the events as text, in the same format as `blkparse`
+        let mut filename = PathBuf::new();
+        filename.set_file_name(prefix.as_ref());
+        filename.set_extension("txt");
+        let mut file = BufWriter::new(File::create(path.as_ref().join(filename))?);
+        write_events(&self.events, &mut file)?;
+
+        // Per-phase request latencies, as `btt` would report them
+        let mut filename = PathBuf::new();
+        filename.set_file_name(prefix.as_ref());
+        filename.set_extension("btt.json");
+        serde_json::to_writer(File::create(path.as_ref().join(filename))?, &self.latency_breakdown())?;
+
+        // Request sizes, merges and queue depth
+        let mut filename = PathBuf::new();
+        filename.set_file_name(prefix.as_ref());
+        filename.set_extension("queue.json");
+        serde_json::to_writer(File::create(path.as_ref().join(filename))?, &self.queue_stats())?;
+
+        // Where on the device the IO went
+        let mut filename = PathBuf::new();
+        filename.set_file_name(prefix.as_ref());
+        filename.set_extension("spatial.json");
+        serde_json::to_writer(File::create(path.as_ref().join(filename))?, &self.spatial_stats())?;
+        let mut filename = PathBuf::new();
+        filename.set_file_name(prefix.as_ref());
+        filename.set_extension("heatmap.csv");
+        let mut file = BufWriter::new(File::create(path.as_ref().join(filename))?);
+        self.lba_heatmap(HEATMAP_REGIONS, Duration::from_millis(HEATMAP_WINDOW_MS))
+            .write_csv(&mut file)?;
         Ok(())
     }
 
@@ -240,39 +717,267 @@ impl Trace {
             .fold(0, |acc, event| acc + event.bytes as usize)
     }
 
-    pub fn total_duration(&self) -> Duration {
-        self.elapsed
+    // Completed IO split by the category flags the kernel attached to each request
+    pub fn io_categories(&self) -> IoCategories {
+        categorize(self.events.iter())
     }
 
-    pub fn io_duration(&self) -> Duration {
-        // Amount of time spent on IO
-        // Count the time from queue insertion to completion
-        let mut inserted = HashMap::new();
-        let mut total_ns: u64 = 0;
-        for event in &self.events {
-            if event.action == Action::Insert {
-                inserted.insert(event.sequence, event.time);
-            } else if event.action == Action::Complete {
-                // check if we have seen this sequence number
-                match inserted.remove(&event.sequence) {
-                    Some(timestamp) => {
-                        assert!(event.time > timestamp);
-                        total_ns += event.time - timestamp;
+    // Devices that appear in the trace, in dev_t order
+    pub fn devices(&self) -> Vec<u32> {
+        let mut devices: Vec<u32> = self.events.iter().map(|event| event.device).collect();
+        devices.sort();
+        devices.dedup();
+        devices
+    }
+
+    // Remap events onto `device`, summed by the device they were remapped from
+    fn remap_sources(&self, device: u32) -> Vec<RemapSource> {
+        let mut sources: Vec<(u32, RemapSource)> = Vec::new();
+        for event in self.events.iter().filter(|event| event.device == device) {
+            if let Payload::Remap { device: from, .. } = event.payload() {
+                let index = match sources.iter().position(|&(d, _)| d == from) {
+                    Some(index) => index,
+                    None => {
+                        sources.push((
+                            from,
+                            RemapSource {
+                                device: format!("{},{}", major(from), minor(from)),
+                                requests: 0,
+                                bytes: 0,
+                            },
+                        ));
+                        sources.len() - 1
                     }
-                    _ => {}
+                };
+                sources[index].1.requests += 1;
+                sources[index].1.bytes += event.bytes as usize;
+            }
+        }
+        sources.into_iter().map(|(_, source)| source).collect()
+    }
+
+    // Completed IO of each traced device
+    pub fn per_device(&self) -> Vec<DeviceIo> {
+        self.devices()
+            .into_iter()
+            .map(|device| {
+                let completed = || {
+                    self.events
+                        .iter()
+                        .filter(move |event| event.device == device && event.action == Action::Complete)
+                };
+                DeviceIo {
+                    device: format!("{},{}", major(device), minor(device)),
+                    reads: completed()
+                        .filter(|event| event.category.contains(Category::READ))
+                        .fold(0, |acc, event| acc + event.bytes as usize),
+                    writes: completed()
+                        .filter(|event| event.category.contains(Category::WRITE))
+                        .fold(0, |acc, event| acc + event.bytes as usize),
+                    io_requests: completed()
+                        .filter(|event| event.category.intersects(Category::READ | Category::WRITE))
+                        .count(),
+                    io_duration: self.requests
+                        .completed
+                        .iter()
+                        .filter(|r| r.device == device)
+                        .filter_map(|r| Request::phase(r.insert.or(r.issue), r.complete))
+                        .fold(Duration::new(0, 0), |acc, d| acc + d),
+                    io_categories: categorize(completed()),
+                    remapped_from: self.remap_sources(device),
                 }
+            })
+            .collect()
+    }
+
+    pub fn start_time(&self) -> u64 {
+        self.start
+    }
+
+    // Time from the start of the task to the last recorded event, or the task duration
+    // if the trace ends before the task did
+    pub fn span(&self) -> Duration {
+        let last_event = self.events
+            .last()
+            .map(|event| Duration::from_nanos(event.time.saturating_sub(self.start)))
+            .unwrap_or(Duration::new(0, 0));
+        if last_event > self.elapsed {
+            last_event
+        } else {
+            self.elapsed
+        }
+    }
+
+    // Completed (read bytes, write bytes) per fixed-size window, starting at the task start
+    pub fn completed_bytes_windows(&self, window: Duration, num_windows: usize) -> Vec<(usize, usize)> {
+        let mut windows = vec![(0, 0); num_windows];
+        let window_nanos = window.as_secs() * 1_000_000_000 + window.subsec_nanos() as u64;
+        for event in self.events.iter().filter(|event| event.action == Action::Complete) {
+            if event.time < self.start {
+                continue;
+            }
+            let index = ((event.time - self.start) / window_nanos) as usize;
+            if index >= num_windows {
+                continue;
+            }
+            if event.category.contains(Category::READ) {
+                windows[index].0 += event.bytes as usize;
+            }
+            if event.category.contains(Category::WRITE) {
+                windows[index].1 += event.bytes as usize;
             }
         }
-        Duration::from_nanos(total_ns)
+        windows
     }
 
+    // Amount of time spent on IO: for every completed request, the time from insertion into
+    // the queue (or issue, on queues without an IO scheduler) to completion
+    pub fn io_duration(&self) -> Duration {
+        self.requests
+            .completed
+            .iter()
+            .filter_map(|r| Request::phase(r.insert.or(r.issue), r.complete))
+            .fold(Duration::new(0, 0), |acc, d| acc + d)
+    }
+
+    // Number of requests that completed during the trace
+    // Completed reads and writes, the same completions completed_reads and completed_writes add up
     pub fn num_requests(&self) -> usize {
-        let mut sequences = HashSet::new();
-        for event in &self.events {
-            if !sequences.contains(&event.sequence) {
-                sequences.insert(event.sequence);
-            }
+        self.events
+            .iter()
+            .filter(|event| {
+                event.action == Action::Complete && event.category.intersects(Category::READ | Category::WRITE)
+            })
+            .count()
+    }
+
+    // Completed requests that could be matched to the bios queued for them, i.e. those the
+    // latency breakdown and per-process request counts are computed from. Fewer than
+    // num_requests if a request was queued before the trace started or its events were lost.
+    pub fn matched_requests(&self) -> usize {
+        self.requests.completed.len()
+    }
+
+    pub fn latency_breakdown(&self) -> LatencyBreakdown {
+        LatencyBreakdown::new(&self.requests.completed)
+    }
+
+    // Sequential vs random issued requests and the seek distances between them
+    pub fn spatial_stats(&self) -> SpatialStats {
+        spatial_stats(&self.events)
+    }
+
+    // Bytes issued per LBA region and time window, starting at the task start
+    pub fn lba_heatmap(&self, num_regions: usize, window: Duration) -> Heatmap {
+        lba_heatmap(&self.events, self.start, num_regions, window)
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            request_sizes: request_sizes(&self.events),
+            merges: merges(&self.events),
+            queue_depth: QueueDepth::new(&depth_changes(&self.events)),
         }
-        sequences.len()
+    }
+
+    // (mean, max) device queue depth per fixed-size window, starting at the task start
+    pub fn queue_depth_windows(&self, window: Duration, num_windows: usize) -> Vec<(f64, usize)> {
+        depth_windows(&depth_changes(&self.events), self.start, window, num_windows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, split_trace_file_name, Action, Category, Trace, TraceSource, MAGIC};
+    use std::time::Duration;
+
+    fn source() -> TraceSource {
+        TraceSource {
+            device: "sdb".to_owned(),
+            cpu: 3,
+        }
+    }
+
+    // One raw record as the kernel lays it out in the relay buffer (native endian)
+    fn record(sequence: u32, action: u32, pdu: &[u8]) -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(&(MAGIC | 7).to_ne_bytes());
+        b.extend_from_slice(&sequence.to_ne_bytes());
+        b.extend_from_slice(&(1000 * sequence as u64).to_ne_bytes());
+        b.extend_from_slice(&2048u64.to_ne_bytes());
+        b.extend_from_slice(&4096u32.to_ne_bytes());
+        b.extend_from_slice(&action.to_ne_bytes());
+        b.extend_from_slice(&42u32.to_ne_bytes());
+        b.extend_from_slice(&(8u32 << 20).to_ne_bytes());
+        b.extend_from_slice(&3u32.to_ne_bytes());
+        b.extend_from_slice(&0u16.to_ne_bytes());
+        b.extend_from_slice(&(pdu.len() as u16).to_ne_bytes());
+        b.extend_from_slice(pdu);
+        b
+    }
+
+    fn write(action: Action) -> u32 {
+        ((Category::WRITE.bits() as u32) << 16) | action as u32
+    }
+
+    #[test]
+    fn parses_records_with_payloads() {
+        let mut data = record(1, write(Action::Queue), &[]);
+        data.extend(record(2, write(Action::Split), &[0, 0, 0, 0, 0, 0, 0x0c, 0]));
+        data.extend(record(3, write(Action::Complete), &[]));
+        let (events, loss) = parse(&data[..], &source()).unwrap();
+        assert!(loss.is_none());
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].action, Action::Split);
+        assert_eq!(events[1].pdu.as_ref().map(|pdu| pdu.data.len()), Some(8));
+        assert_eq!((events[2].time, events[2].sector, events[2].bytes), (3000, 2048, 4096));
+        assert_eq!((events[2].pid, events[2].cpu), (42, 3));
+        assert!(events[2].category.contains(Category::WRITE));
+    }
+
+    #[test]
+    fn reports_sequence_gaps_and_stops_at_corruption() {
+        let mut data = record(1, write(Action::Queue), &[]);
+        data.extend(record(5, write(Action::Queue), &[]));
+        data.extend(vec![0xff; 64]);
+        let (events, loss) = parse(&data[..], &source()).unwrap();
+        assert_eq!(events.len(), 2);
+        let loss = loss.expect("loss should be reported");
+        assert_eq!((&loss.device[..], loss.cpu), ("sdb", 3));
+        assert_eq!((loss.missing_events, loss.corrupt), (3, true));
+    }
+
+    #[test]
+    fn ignores_a_truncated_last_record() {
+        let mut data = record(1, write(Action::Queue), &[]);
+        let second = record(2, write(Action::Split), &[0; 8]);
+        data.extend_from_slice(&second[..second.len() - 4]);
+        let (events, loss) = parse(&data[..], &source()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(loss.is_none());
+    }
+
+    #[test]
+    fn requests_count_every_completion() {
+        // Completed before its bio could be queued in the trace, then a queued and completed one
+        let mut data = record(1, write(Action::Complete), &[]);
+        data.extend(record(2, write(Action::Queue), &[]));
+        data.extend(record(3, write(Action::Issue), &[]));
+        data.extend(record(4, write(Action::Complete), &[]));
+        let trace = Trace::new(vec![source()], vec![data], 0, Duration::from_secs(1));
+        assert_eq!(trace.num_requests(), 2);
+        assert_eq!(trace.completed_writes(), 2 * 4096);
+        assert_eq!(trace.matched_requests(), 1);
+        assert_eq!(trace.per_device()[0].io_requests, 2);
+    }
+
+    #[test]
+    fn trace_file_names_round_trip() {
+        let name = source().file_name("blktrace");
+        assert_eq!(name, "blktrace.sdb.blktrace.3");
+        assert_eq!(split_trace_file_name(&name), Some(("blktrace", "sdb", 3)));
+        // Written by blktrace itself
+        assert_eq!(split_trace_file_name("nvme0n1p2.blktrace.12"), Some(("nvme0n1p2", "nvme0n1p2", 12)));
+        assert_eq!(split_trace_file_name("blktrace.txt"), None);
     }
 }
@@ -0,0 +1,317 @@
+// Block layer IO counters the kernel keeps for every block device, read from
+// /sys/class/block/<dev>/stat (or /proc/diskstats if that isn't there). Unlike blktrace they need
+// no debugfs or CONFIG_BLK_DEV_IO_TRACE and cost nothing while the benchmark runs, but they are
+// totals only and count IO from every process on the device. Field layout is described in
+// Documentation/block/stat.rst.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Unit of the sector counts, regardless of the device's logical block size
+const SECTOR_SIZE: u64 = 512;
+// Relative difference between the blktrace and diskstats byte counts that's still considered
+// consistent. The two are sampled at slightly different times.
+const CROSS_CHECK_TOLERANCE: f64 = 0.05;
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Counters {
+    // Completed IOs
+    pub ios: u64,
+    // Adjacent IOs merged into one before being issued
+    pub merges: u64,
+    pub sectors: u64,
+    // Total time (ms) the IOs took from being queued to completing
+    pub ticks_ms: u64,
+}
+
+impl Counters {
+    pub fn bytes(&self) -> u64 {
+        self.sectors * SECTOR_SIZE
+    }
+
+    fn since(&self, before: &Counters) -> Counters {
+        Counters {
+            ios: self.ios.saturating_sub(before.ios),
+            merges: self.merges.saturating_sub(before.merges),
+            sectors: self.sectors.saturating_sub(before.sectors),
+            ticks_ms: self.ticks_ms.saturating_sub(before.ticks_ms),
+        }
+    }
+}
+
+// Discards need Linux 4.18 and flushes 5.5; older kernels leave them at 0
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct DiskStats {
+    pub reads: Counters,
+    pub writes: Counters,
+    pub discards: Counters,
+    // Flushes have no merges or sectors
+    pub flushes: Counters,
+    // IOs in flight when the stats were read
+    pub in_flight: u64,
+    // Time (ms) the device had at least one IO in flight
+    pub io_ticks_ms: u64,
+    // Time (ms) IOs spent in flight, weighted by the number in flight
+    pub time_in_queue_ms: u64,
+}
+
+impl DiskStats {
+    // The fields of a stat file, or of a /proc/diskstats line after major, minor and name
+    fn parse(line: &str) -> io::Result<DiskStats> {
+        let fields = line
+            .split_whitespace()
+            .map(|field| field.parse())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("bad counter in `{}`: {}", line.trim(), e)))?;
+        if fields.len() < 11 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected at least 11 fields in `{}`", line.trim()),
+            ));
+        }
+        let field = |index: usize| fields.get(index).cloned().unwrap_or(0);
+        let counters = |first: usize| Counters {
+            ios: field(first),
+            merges: field(first + 1),
+            sectors: field(first + 2),
+            ticks_ms: field(first + 3),
+        };
+        Ok(DiskStats {
+            reads: counters(0),
+            writes: counters(4),
+            in_flight: field(8),
+            io_ticks_ms: field(9),
+            time_in_queue_ms: field(10),
+            discards: counters(11),
+            flushes: Counters {
+                ios: field(15),
+                ticks_ms: field(16),
+                ..Default::default()
+            },
+        })
+    }
+
+    // Counters accumulated since `before`. in_flight is a gauge and keeps its current value.
+    fn since(&self, before: &DiskStats) -> DiskStats {
+        DiskStats {
+            reads: self.reads.since(&before.reads),
+            writes: self.writes.since(&before.writes),
+            discards: self.discards.since(&before.discards),
+            flushes: self.flushes.since(&before.flushes),
+            in_flight: self.in_flight,
+            io_ticks_ms: self.io_ticks_ms.saturating_sub(before.io_ticks_ms),
+            time_in_queue_ms: self.time_in_queue_ms.saturating_sub(before.time_in_queue_ms),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct DeviceStats {
+    // Kernel name, e.g. "sda1" or "dm-0"
+    pub device: String,
+    pub stats: DiskStats,
+}
+
+// Reads the stats of a fixed set of block devices
+pub struct DiskStatsCollector {
+    // Kernel names of the devices
+    devices: Vec<String>,
+}
+
+// Kernel name of a block device node, following symlinks such as /dev/mapper/* or
+// /dev/disk/by-id/*
+pub fn kernel_name(path: &Path) -> String {
+    let resolved = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    resolved
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// The block device the filesystem at `path` is on, from its st_dev. Fails for filesystems that
+// aren't on one, such as tmpfs or NFS.
+pub fn backing_device(path: &Path) -> io::Result<PathBuf> {
+    use super::nix::sys::stat::{major, minor};
+    use std::os::unix::fs::MetadataExt;
+    let dev = fs::metadata(path)?.dev();
+    let sysfs = PathBuf::from(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)));
+    let name = fs::canonicalize(&sysfs)
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not on a block device", path)))?
+        .file_name()
+        .map(|name| name.to_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not on a block device", path)))?;
+    Ok(PathBuf::from("/dev").join(name))
+}
+
+fn read_stats(device: &str) -> io::Result<DiskStats> {
+    let stat_path = PathBuf::from("/sys/class/block").join(device).join("stat");
+    if let Ok(contents) = fs::read_to_string(&stat_path) {
+        return DiskStats::parse(&contents);
+    }
+    let diskstats = fs::read_to_string("/proc/diskstats")?;
+    find_in_diskstats(&diskstats, device).unwrap_or_else(|| {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not in {:?} or /proc/diskstats", device, stat_path),
+        ))
+    })
+}
+
+// The stats of `device` in the contents of /proc/diskstats, None if it isn't listed
+fn find_in_diskstats(diskstats: &str, device: &str) -> Option<io::Result<DiskStats>> {
+    for line in diskstats.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.nth(2) == Some(device) {
+            let rest: Vec<&str> = fields.collect();
+            return Some(DiskStats::parse(&rest.join(" ")));
+        }
+    }
+    None
+}
+
+impl DiskStatsCollector {
+    pub fn new(paths: &[PathBuf]) -> Self {
+        DiskStatsCollector {
+            devices: paths.iter().map(|path| kernel_name(path)).collect(),
+        }
+    }
+
+    // Current counters of every device, in the order they were given to `new`
+    pub fn snapshot(&self) -> io::Result<Vec<DiskStats>> {
+        self.devices.iter().map(|device| read_stats(device)).collect()
+    }
+
+    // Counters accumulated since `before` was taken with `snapshot`
+    pub fn since(&self, before: &[DiskStats]) -> io::Result<Vec<DeviceStats>> {
+        let after = self.snapshot()?;
+        Ok(self.devices
+            .iter()
+            .zip(after.iter().zip(before.iter()))
+            .map(|(device, (after, before))| DeviceStats {
+                device: device.clone(),
+                stats: after.since(before),
+            })
+            .collect())
+    }
+}
+
+// Bytes read and written on all devices; writes include discards, as in Trace::completed_writes
+pub fn total_bytes(stats: &[DeviceStats]) -> (usize, usize) {
+    stats.iter().fold((0, 0), |(reads, writes), device| {
+        (
+            reads + device.stats.reads.bytes() as usize,
+            writes + (device.stats.writes.bytes() + device.stats.discards.bytes()) as usize,
+        )
+    })
+}
+
+// Completed IOs of every kind on all devices
+pub fn total_ios(stats: &[DeviceStats]) -> usize {
+    stats.iter().fold(0, |acc, device| {
+        let s = &device.stats;
+        acc + (s.reads.ios + s.writes.ios + s.discards.ios + s.flushes.ios) as usize
+    })
+}
+
+// Completed bytes as blktrace saw them next to the block layer's own counters. They should
+// agree unless blktrace lost events or the counters were sampled over a different window.
+#[derive(Serialize)]
+pub struct DiskStatsCheck {
+    pub trace_reads: usize,
+    pub diskstats_reads: usize,
+    pub trace_writes: usize,
+    pub diskstats_writes: usize,
+    pub consistent: bool,
+}
+
+fn within_tolerance(a: usize, b: usize) -> bool {
+    let larger = a.max(b);
+    larger == 0 || (larger - a.min(b)) as f64 / larger as f64 <= CROSS_CHECK_TOLERANCE
+}
+
+impl DiskStatsCheck {
+    pub fn new(stats: &[DeviceStats], trace_reads: usize, trace_writes: usize) -> DiskStatsCheck {
+        let (diskstats_reads, diskstats_writes) = total_bytes(stats);
+        DiskStatsCheck {
+            trace_reads: trace_reads,
+            diskstats_reads: diskstats_reads,
+            trace_writes: trace_writes,
+            diskstats_writes: diskstats_writes,
+            consistent: within_tolerance(trace_reads, diskstats_reads) && within_tolerance(trace_writes, diskstats_writes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // /sys/class/block/<dev>/stat on Linux 5.5 or later, with discard and flush fields
+    const STAT: &str = "  123456     2345  9876543    45678    65432    12345  8765432   987654        3   234567  1033332     \
+                        1000        0   204800      120     4321     5678\n";
+
+    #[test]
+    fn parses_stat_file() {
+        let stats = DiskStats::parse(STAT).unwrap();
+        assert_eq!(
+            (stats.reads.ios, stats.reads.merges, stats.reads.sectors, stats.reads.ticks_ms),
+            (123456, 2345, 9876543, 45678)
+        );
+        assert_eq!(
+            (stats.writes.ios, stats.writes.merges, stats.writes.sectors, stats.writes.ticks_ms),
+            (65432, 12345, 8765432, 987654)
+        );
+        assert_eq!((stats.in_flight, stats.io_ticks_ms, stats.time_in_queue_ms), (3, 234567, 1033332));
+        assert_eq!(
+            (stats.discards.ios, stats.discards.merges, stats.discards.sectors, stats.discards.ticks_ms),
+            (1000, 0, 204800, 120)
+        );
+        assert_eq!((stats.flushes.ios, stats.flushes.sectors, stats.flushes.ticks_ms), (4321, 0, 5678));
+        assert_eq!(stats.writes.bytes(), 8765432 * 512);
+    }
+
+    #[test]
+    fn parses_stat_file_of_old_kernels() {
+        // Before Linux 4.18 there are only the first 11 fields
+        let line = "    4511      220   282690     1532     9263     8126   398720    12292        0     5716    13824";
+        let stats = DiskStats::parse(line).unwrap();
+        assert_eq!(stats.reads.sectors, 282690);
+        assert_eq!(stats.writes.ios, 9263);
+        assert_eq!(stats.time_in_queue_ms, 13824);
+        assert_eq!(stats.discards.ios, 0);
+        assert_eq!(stats.flushes.ios, 0);
+
+        assert!(DiskStats::parse("4511 220 282690 1532 9263").is_err());
+        // A counter that doesn't parse would otherwise read as 0
+        let error = DiskStats::parse("4511 220 282690 1532 9263 8126 39872x 12292 0 5716 13824").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(DiskStats::parse("").is_err());
+    }
+
+    #[test]
+    fn finds_device_in_proc_diskstats() {
+        let diskstats = "   7       0 loop0 52 0 2158 21 0 0 0 0 0 44 21 0 0 0 0 0 0
+ 259       0 nvme0n1 187236 61420 12964058 47153 411523 341292 30925432 509344 0 393564 593460 0 0 0 0 23617 36962
+ 259       1 nvme0n1p1 317 1181 13338 63 2 0 2 4 0 92 68 0 0 0 0 0 0
+";
+        let stats = find_in_diskstats(diskstats, "nvme0n1").unwrap().unwrap();
+        assert_eq!(stats.reads.ios, 187236);
+        assert_eq!(stats.writes.sectors, 30925432);
+        assert_eq!(stats.flushes.ios, 23617);
+        // Names are matched exactly, not by prefix
+        assert_eq!(find_in_diskstats(diskstats, "nvme0n1p1").unwrap().unwrap().reads.ios, 317);
+        assert!(find_in_diskstats(diskstats, "nvme0").is_none());
+    }
+
+    #[test]
+    fn since_subtracts_counters_but_not_in_flight() {
+        let before = DiskStats::parse("10 1 80 5 20 2 160 7 4 30 40").unwrap();
+        let after = DiskStats::parse("15 1 120 9 32 6 400 19 1 45 70").unwrap();
+        let delta = after.since(&before);
+        assert_eq!((delta.reads.ios, delta.reads.sectors), (5, 40));
+        assert_eq!((delta.writes.ios, delta.writes.merges, delta.writes.sectors), (12, 4, 240));
+        assert_eq!((delta.in_flight, delta.io_ticks_ms, delta.time_in_queue_ms), (1, 15, 30));
+        // A device whose counters were reset reads as no IO rather than underflowing
+        assert_eq!(before.since(&after).reads.ios, 0);
+    }
+}
@@ -0,0 +1,568 @@
+// Filesystem tracepoints collected with ftrace: what the filesystem did on top of the block IO
+// blktrace sees, e.g. how many journal commits the benchmark's fsyncs caused and how long they
+// took. Events are recorded into a private tracefs instance so they don't mix with (or clear)
+// the global trace buffer.
+use super::diskstats::kernel_name;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Tracepoints recorded unless others are given with `--ftrace-events`. `*` matches any suffix.
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "ext4:ext4_sync_file_enter",
+    "ext4:ext4_sync_file_exit",
+    "jbd2:jbd2_start_commit",
+    "jbd2:jbd2_commit_*",
+    "jbd2:jbd2_end_commit",
+    "xfs:xfs_file_fsync",
+    "xfs:xfs_log_force",
+    "btrfs:btrfs_sync_file",
+    "btrfs:btrfs_transaction_commit",
+    "f2fs:f2fs_sync_file_*",
+    // Not writeback:*, whose writeback_dirty_* events fire for every dirtied page and overflow
+    // the buffer long before `stop` reads it
+    "writeback:writeback_queue",
+    "writeback:writeback_exec",
+    "writeback:writeback_start",
+    "writeback:writeback_written",
+    "writeback:writeback_pages_written",
+    "writeback:writeback_single_inode",
+];
+
+// Name of the tracefs instance fsbench records into
+const INSTANCE: &str = "fsbench";
+// Per-CPU ring buffer size. The buffer is only read once recording stops, so events that fire
+// per page (see DEFAULT_EVENTS) can still fill it; they show up in `lost_events`.
+const BUFFER_SIZE_KB: usize = 8192;
+
+// Pairs of events that start and end an interval in the same task, named after what they time
+const SPANS: &[(&str, &str, &str)] = &[
+    ("ext4_sync_file", "ext4_sync_file_enter", "ext4_sync_file_exit"),
+    ("f2fs_sync_file", "f2fs_sync_file_enter", "f2fs_sync_file_exit"),
+    ("jbd2_commit", "jbd2_start_commit", "jbd2_end_commit"),
+    ("writeback", "writeback_start", "writeback_written"),
+];
+// Events marking an fsync, and a journal commit or log force, on each filesystem
+const FSYNC_EVENTS: &[&str] = &["ext4_sync_file_enter", "f2fs_sync_file_enter", "xfs_file_fsync", "btrfs_sync_file"];
+const COMMIT_EVENTS: &[&str] = &["jbd2_end_commit", "xfs_log_force", "btrfs_transaction_commit"];
+// Subsystems whose events have a `dev` field (the filesystem's dev_t). btrfs events only have
+// the filesystem's fsid, which filters can't match, so they are sorted out in `summarize`.
+const DEV_FILTERED: &[&str] = &["ext4", "jbd2", "xfs", "f2fs"];
+// Where each mounted btrfs has a directory named after its fsid
+const BTRFS_SYSFS: &str = "/sys/fs/btrfs";
+
+#[derive(Clone, Serialize)]
+pub struct EventCount {
+    pub event: String,
+    pub count: usize,
+}
+
+#[derive(Clone, Serialize)]
+pub struct SpanStats {
+    pub span: String,
+    // Completed intervals; an end event without a start (or the other way round) isn't counted
+    pub count: usize,
+    pub total: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+}
+
+#[derive(Clone, Serialize)]
+pub struct FsEvents {
+    // Sorted by count, most frequent first
+    pub events: Vec<EventCount>,
+    pub spans: Vec<SpanStats>,
+    pub fsyncs: usize,
+    // Journal commits (ext4, btrfs) or log forces (XFS)
+    pub journal_commits: usize,
+    // None if there were no fsyncs
+    pub commits_per_fsync: Option<f64>,
+    // Events the kernel dropped because the ring buffer was full
+    pub lost_events: usize,
+    // btrfs events left out because they belong to another btrfs filesystem, or could (on
+    // kernels that don't print the fsid) while another one is mounted
+    pub other_fs_events: usize,
+}
+
+// One line of the `trace` file
+struct TraceLine<'a> {
+    pid: u32,
+    // ns, CLOCK_MONOTONIC
+    time: u64,
+    event: &'a str,
+    // What follows the event name
+    details: &'a str,
+}
+
+// e.g. "  fsbench-1234    [002] .....  5678.123456: ext4_sync_file_enter: dev 7,0 ino 12 ..."
+fn parse_line(line: &str) -> Option<TraceLine<'_>> {
+    let cpu = line.find(" [")?;
+    let pid = line[..cpu].trim().rsplit('-').next()?.parse().ok()?;
+    let mut fields = line[cpu..].split_whitespace().skip_while(|field| !field.ends_with(':'));
+    let timestamp = fields.next()?.trim_end_matches(':');
+    let event = fields.next()?.trim_end_matches(':');
+    let details = line[cpu..].splitn(2, &format!(" {}: ", event)[..]).nth(1).unwrap_or("").trim();
+    let mut parts = timestamp.splitn(2, '.');
+    let secs: u64 = parts.next()?.parse().ok()?;
+    let micros: u64 = parts.next()?.parse().ok()?;
+    Some(TraceLine {
+        pid: pid,
+        time: secs * 1_000_000_000 + micros * 1_000,
+        event: event,
+        details: details,
+    })
+}
+
+// The fsid btrfs events start with since Linux 4.8, e.g. "c5b3f2a0-...-3e1d4c7b9a21: root=5 ..."
+fn btrfs_fsid(details: &str) -> Option<&str> {
+    let fsid = details.split(':').next()?;
+    let is_uuid = fsid.len() == 36 && fsid.chars().all(|c| c == '-' || c.is_digit(16));
+    if is_uuid {
+        Some(fsid)
+    } else {
+        None
+    }
+}
+
+// Which btrfs events belong to the benchmarked filesystem
+struct BtrfsFilter {
+    // fsids of the btrfs filesystems on the traced devices
+    fsids: Vec<String>,
+    // Whether any other btrfs is mounted
+    others_mounted: bool,
+}
+
+impl BtrfsFilter {
+    fn keeps(&self, details: &str) -> bool {
+        match btrfs_fsid(details) {
+            Some(fsid) => self.fsids.iter().any(|id| id.eq_ignore_ascii_case(fsid)),
+            None => !self.others_mounted,
+        }
+    }
+}
+
+// e.g. "CPU:3 [LOST 1234 EVENTS]"
+fn lost_events(line: &str) -> Option<usize> {
+    let start = line.find("[LOST ")? + "[LOST ".len();
+    line[start..].split_whitespace().next()?.parse().ok()
+}
+
+// `btrfs` is None if every btrfs event is kept
+fn summarize(trace: &str, btrfs: Option<&BtrfsFilter>) -> FsEvents {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut lost = 0;
+    let mut other_fs = 0;
+    // Start time of each open span by (span, pid), and the finished durations by span
+    let mut open: HashMap<(usize, u32), u64> = HashMap::new();
+    let mut durations: Vec<Vec<u64>> = SPANS.iter().map(|_| Vec::new()).collect();
+    for line in trace.lines().filter(|line| !line.starts_with('#')) {
+        if let Some(n) = lost_events(line) {
+            lost += n;
+            continue;
+        }
+        let line = match parse_line(line) {
+            Some(line) => line,
+            None => continue,
+        };
+        if line.event.starts_with("btrfs_") && !btrfs.map_or(true, |btrfs| btrfs.keeps(line.details)) {
+            other_fs += 1;
+            continue;
+        }
+        *counts.entry(line.event).or_insert(0) += 1;
+        for (index, &(_, start, end)) in SPANS.iter().enumerate() {
+            if line.event == start {
+                open.insert((index, line.pid), line.time);
+            } else if line.event == end {
+                if let Some(started) = open.remove(&(index, line.pid)) {
+                    durations[index].push(line.time.saturating_sub(started));
+                }
+            }
+        }
+    }
+
+    let sum = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| counts.get(name).cloned().unwrap_or(0))
+            .fold(0, |acc, n| acc + n)
+    };
+    let fsyncs = sum(FSYNC_EVENTS);
+    let journal_commits = sum(COMMIT_EVENTS);
+    let mut events: Vec<EventCount> = counts
+        .iter()
+        .map(|(event, count)| EventCount {
+            event: event.to_string(),
+            count: *count,
+        })
+        .collect();
+    events.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.event.cmp(&b.event)));
+    let spans = SPANS
+        .iter()
+        .zip(durations.iter())
+        .filter(|&(_, durations)| !durations.is_empty())
+        .map(|(&(span, _, _), durations)| {
+            let total = durations.iter().fold(0, |acc, d| acc + d);
+            SpanStats {
+                span: span.to_owned(),
+                count: durations.len(),
+                total: Duration::from_nanos(total),
+                mean: Duration::from_nanos(total / durations.len() as u64),
+                max: Duration::from_nanos(durations.iter().cloned().max().unwrap_or(0)),
+            }
+        })
+        .collect();
+    FsEvents {
+        events: events,
+        spans: spans,
+        fsyncs: fsyncs,
+        journal_commits: journal_commits,
+        commits_per_fsync: if fsyncs > 0 {
+            Some(journal_commits as f64 / fsyncs as f64)
+        } else {
+            None
+        },
+        lost_events: lost,
+        other_fs_events: other_fs,
+    }
+}
+
+// Whether `event` ("system:name") matches `pattern`, which may end in `*`
+fn matches(pattern: &str, event: &str) -> bool {
+    if pattern.ends_with('*') {
+        event.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        event == pattern
+    }
+}
+
+fn write_file<P: AsRef<Path>>(path: P, contents: &str) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+// A filter that fails only means more events than asked for, so it's not fatal
+fn set_filter(path: &Path, filter: &str) {
+    if let Err(e) = write_file(path, filter) {
+        warn!("failed to set ftrace filter `{}` in {:?}: {}", filter, path, e);
+    }
+}
+
+// The directory in /sys/fs/btrfs of the mounted btrfs that `device` (a kernel name) is part of
+fn btrfs_fs_dir(device: &str) -> Option<PathBuf> {
+    fs::read_dir(BTRFS_SYSFS)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|dir| dir.join("devices").join(device).exists())
+}
+
+pub struct Ftrace {
+    // tracefs mount point
+    tracefs: PathBuf,
+    instance: PathBuf,
+    patterns: Vec<String>,
+    // Kernel names of the traced devices
+    names: Vec<String>,
+    // "major:minor" of the traced devices, and of the disks they are on
+    devices: Vec<String>,
+    disks: Vec<String>,
+}
+
+// "major:minor" of a block device, and of the whole disk if it's a partition
+fn device_numbers(path: &Path) -> io::Result<(String, String)> {
+    let sysfs = fs::canonicalize(PathBuf::from("/sys/class/block").join(kernel_name(path)))?;
+    let device = fs::read_to_string(sysfs.join("dev"))?.trim().to_owned();
+    let disk = if sysfs.join("partition").exists() {
+        match sysfs.parent() {
+            Some(parent) => fs::read_to_string(parent.join("dev"))?.trim().to_owned(),
+            None => device.clone(),
+        }
+    } else {
+        device.clone()
+    };
+    Ok((device, disk))
+}
+
+// dev_t as the kernel stores it in tracepoints (MINORBITS = 20)
+fn kernel_dev(numbers: &str) -> Option<u64> {
+    let mut parts = numbers.splitn(2, ':');
+    let major: u64 = parts.next()?.parse().ok()?;
+    let minor: u64 = parts.next()?.parse().ok()?;
+    Some(major << 20 | minor)
+}
+
+impl Ftrace {
+    // Creates the tracefs instance. `patterns` are "system:event" names as in DEFAULT_EVENTS;
+    // events of the listed systems that have a device are limited to `devices`.
+    pub fn new<P: AsRef<Path>>(debugfs_path: P, devices: &[PathBuf], patterns: Vec<String>) -> io::Result<Self> {
+        // tracefs has its own mount point since Linux 4.1; older kernels only have it in debugfs
+        let tracefs = [PathBuf::from("/sys/kernel/tracing"), debugfs_path.as_ref().join("tracing")]
+            .iter()
+            .find(|path| path.join("available_events").exists())
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracefs is not mounted"))?;
+        let instance = tracefs.join("instances").join(INSTANCE);
+        // Left behind if a previous run was killed
+        if !instance.is_dir() {
+            fs::create_dir(&instance)?;
+        }
+        write_file(instance.join("tracing_on"), "0")?;
+        // Same clock as blktrace and the operation start times
+        write_file(instance.join("trace_clock"), "mono")?;
+        write_file(instance.join("buffer_size_kb"), &BUFFER_SIZE_KB.to_string())?;
+        let mut numbers = Vec::new();
+        let mut disks = Vec::new();
+        for device in devices {
+            let (device, disk) = device_numbers(device)?;
+            numbers.push(device);
+            disks.push(disk);
+        }
+        Ok(Ftrace {
+            tracefs: tracefs,
+            instance: instance,
+            patterns: patterns,
+            names: devices.iter().map(|device| kernel_name(device)).collect(),
+            devices: numbers,
+            disks: disks,
+        })
+    }
+
+    // Clears the buffer and enables the events. Events are looked up here rather than in `new`
+    // because a filesystem's tracepoints only exist once its module is loaded.
+    pub fn start(&self) -> io::Result<()> {
+        write_file(self.instance.join("tracing_on"), "0")?;
+        write_file(self.instance.join("trace"), "")?;
+        write_file(self.instance.join("set_event"), "")?;
+        let available = fs::read_to_string(self.tracefs.join("available_events"))?;
+        let events: Vec<&str> = available
+            .lines()
+            .filter(|event| self.patterns.iter().any(|pattern| matches(pattern, event)))
+            .collect();
+        if events.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "none of the ftrace events are available"));
+        }
+        for event in &events {
+            // One per write; set_event doesn't take a list
+            OpenOptions::new()
+                .append(true)
+                .open(self.instance.join("set_event"))?
+                .write_all(event.as_bytes())?;
+        }
+
+        // Without devices (a filesystem used as is at --mount-path) every event is kept
+        if !self.devices.is_empty() {
+            self.set_filters();
+        }
+        write_file(self.instance.join("tracing_on"), "1")
+    }
+
+    // Limits the events to the traced devices
+    fn set_filters(&self) {
+        // Filters on fields a subsystem's events don't all have are skipped for those events
+        let dev_filter = self
+            .devices
+            .iter()
+            .filter_map(|device| kernel_dev(device))
+            .map(|dev| format!("dev == {}", dev))
+            .collect::<Vec<String>>()
+            .join(" || ");
+        for system in DEV_FILTERED {
+            let filter = self.instance.join("events").join(system).join("filter");
+            if filter.exists() {
+                set_filter(&filter, &dev_filter);
+            }
+        }
+        // Writeback events name the disk's backing_dev_info instead
+        let filter = self.instance.join("events/writeback/filter");
+        if filter.exists() {
+            match self.bdi_names() {
+                Some(names) => {
+                    let bdi_filter = names
+                        .iter()
+                        .map(|name| format!("name == \"{}\"", name))
+                        .collect::<Vec<String>>()
+                        .join(" || ");
+                    set_filter(&filter, &bdi_filter);
+                }
+                None => {
+                    warn!("can't tell which backing_dev_info btrfs writes back through, recording writeback of all devices");
+                    set_filter(&filter, "0");
+                }
+            }
+        }
+    }
+
+    // Names of the backing_dev_infos the traced devices are written back through: the disk's,
+    // except for btrfs, which has its own ("btrfs-N") per filesystem. That one is linked from
+    // <BTRFS_SYSFS>/<fsid>/bdi since Linux 5.9; None on older kernels.
+    fn bdi_names(&self) -> Option<Vec<String>> {
+        let mut names = Vec::new();
+        for (name, disk) in self.names.iter().zip(self.disks.iter()) {
+            match btrfs_fs_dir(name) {
+                Some(dir) => {
+                    let bdi = fs::read_link(dir.join("bdi")).ok()?;
+                    names.push(bdi.file_name()?.to_string_lossy().into_owned());
+                }
+                None => names.push(disk.clone()),
+            }
+        }
+        Some(names)
+    }
+
+    // The btrfs filesystems on the traced devices and whether others are mounted. Looked up
+    // when recording stops, as each run creates the filesystem (and its fsid) anew. None without
+    // devices, like the other filters.
+    fn btrfs_filter(&self) -> Option<BtrfsFilter> {
+        if self.devices.is_empty() {
+            return None;
+        }
+        let fsids: Vec<String> = self
+            .names
+            .iter()
+            .filter_map(|name| btrfs_fs_dir(name))
+            .filter_map(|dir| dir.file_name().map(|fsid| fsid.to_string_lossy().into_owned()))
+            .collect();
+        let others_mounted = fs::read_dir(BTRFS_SYSFS)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .any(|name| name != "features" && !fsids.contains(&name))
+            })
+            .unwrap_or(false);
+        Some(BtrfsFilter {
+            fsids: fsids,
+            others_mounted: others_mounted,
+        })
+    }
+
+    // Stops recording and summarizes the events since `start`
+    pub fn stop(&self) -> io::Result<FsEvents> {
+        write_file(self.instance.join("tracing_on"), "0")?;
+        let trace = fs::read_to_string(self.instance.join("trace"))?;
+        write_file(self.instance.join("set_event"), "")?;
+        let events = summarize(&trace, self.btrfs_filter().as_ref());
+        if events.other_fs_events > 0 {
+            info!("left out {} events of other btrfs filesystems", events.other_fs_events);
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for Ftrace {
+    fn drop(&mut self) {
+        let _ = write_file(self.instance.join("tracing_on"), "0");
+        let _ = write_file(self.instance.join("set_event"), "");
+        if let Err(e) = fs::remove_dir(&self.instance) {
+            warn!("failed to remove tracefs instance {:?}: {}", self.instance, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Captured from an ext4 instance, with a lost-events marker added
+    const TRACE: &str = "# tracer: nop
+#
+# entries-in-buffer/entries-written: 9/9   #P:4
+#
+#           TASK-PID     CPU#  |||||  TIMESTAMP  FUNCTION
+#              | |         |   |||||     |         |
+         fsbench-4242    [001] .....  1234.500000: ext4_sync_file_enter: dev 259,1 ino 131074 parent 131073 datasync 0
+ jbd2/nvme0n1p1-8-301     [003] .....  1234.500100: jbd2_start_commit: dev 259,1 transaction 1234 sync 0
+ jbd2/nvme0n1p1-8-301     [003] .....  1234.502600: jbd2_end_commit: dev 259,1 transaction 1234 sync 0 head 1230
+         fsbench-4242    [001] .....  1234.503000: ext4_sync_file_exit: dev 259,1 ino 131074 ret 0
+CPU:2 [LOST 17 EVENTS]
+         fsbench-4243    [002] .....  1234.600000: ext4_sync_file_enter: dev 259,1 ino 131075 parent 131073 datasync 0
+         fsbench-4243    [002] .....  1234.601000: ext4_sync_file_exit: dev 259,1 ino 131075 ret 0
+ kworker/u8:2-77      [000] .....  1235.000000: writeback_written: bdi 259:0: sb_dev 0:0 nr_pages=12 sync_mode=0
+ kworker/u8:2-77      [000] .....  1235.100000: writeback_start: bdi 259:0: sb_dev 0:0 nr_pages=9 sync_mode=0
+";
+
+    #[test]
+    fn parses_trace_lines() {
+        let line = parse_line(" jbd2/nvme0n1p1-8-301     [003] .....  1234.500100: jbd2_start_commit: dev 259,1").unwrap();
+        assert_eq!(line.pid, 301);
+        assert_eq!(line.time, 1_234_500_100_000);
+        assert_eq!(line.event, "jbd2_start_commit");
+        assert_eq!(line.details, "dev 259,1");
+        // Older kernels print four flags
+        let line = parse_line("           <...>-12    [000] d..1    10.000001: writeback_queue: bdi 8:0").unwrap();
+        assert_eq!((line.pid, line.time, line.event), (12, 10_000_001_000, "writeback_queue"));
+
+        assert!(parse_line("#           TASK-PID     CPU#  |||||  TIMESTAMP  FUNCTION").is_none());
+        assert!(parse_line("CPU:2 [LOST 17 EVENTS]").is_none());
+        assert_eq!(lost_events("CPU:2 [LOST 17 EVENTS]"), Some(17));
+        assert_eq!(lost_events(" fsbench-4242 [001] .....  1234.500000: ext4_sync_file_enter:"), None);
+    }
+
+    #[test]
+    fn summarizes_events_and_spans() {
+        let events = summarize(TRACE, None);
+        let counts: Vec<(&str, usize)> = events.events.iter().map(|e| (&e.event[..], e.count)).collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("ext4_sync_file_enter", 2),
+                ("ext4_sync_file_exit", 2),
+                ("jbd2_end_commit", 1),
+                ("jbd2_start_commit", 1),
+                ("writeback_start", 1),
+                ("writeback_written", 1),
+            ]
+        );
+        assert_eq!(events.fsyncs, 2);
+        assert_eq!(events.journal_commits, 1);
+        assert_eq!(events.commits_per_fsync, Some(0.5));
+        assert_eq!(events.lost_events, 17);
+
+        let spans: Vec<(&str, usize, Duration, Duration)> = events.spans.iter().map(|s| (&s.span[..], s.count, s.total, s.max)).collect();
+        // The writeback pass that ends before it starts isn't a span
+        assert_eq!(
+            spans,
+            vec![
+                ("ext4_sync_file", 2, Duration::from_micros(4000), Duration::from_micros(3000)),
+                ("jbd2_commit", 1, Duration::from_micros(2500), Duration::from_micros(2500)),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_btrfs_events_of_the_traced_filesystem() {
+        let trace = "\
+ fsbench-4242    [001] .....  100.000000: btrfs_sync_file: 1b4e28ba-2fa1-11d2-883f-0016d3cca427: root=5 ino=257 datasync=0
+ btrfs-transacti-88 [000] .....  100.001000: btrfs_transaction_commit: 1B4E28BA-2FA1-11D2-883F-0016D3CCA427: gen=12
+ btrfs-transacti-99 [002] .....  100.002000: btrfs_transaction_commit: 6fa459ea-ee8a-3ca4-894e-db77e160355e: gen=40
+ fsbench-4242    [001] .....  100.003000: btrfs_sync_file: root=5 ino=257 datasync=0
+";
+        let mut filter = BtrfsFilter {
+            fsids: vec!["1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_owned()],
+            others_mounted: true,
+        };
+        // Without the fsid (before Linux 4.8) the last fsync could be the other filesystem's
+        let events = summarize(trace, Some(&filter));
+        assert_eq!((events.fsyncs, events.journal_commits, events.other_fs_events), (1, 1, 2));
+        filter.others_mounted = false;
+        let events = summarize(trace, Some(&filter));
+        assert_eq!((events.fsyncs, events.journal_commits, events.other_fs_events), (2, 1, 1));
+        let events = summarize(trace, None);
+        assert_eq!((events.fsyncs, events.journal_commits, events.other_fs_events), (2, 2, 0));
+    }
+
+    #[test]
+    fn no_fsyncs_no_ratio() {
+        let events = summarize("# tracer: nop\n", None);
+        assert!(events.events.is_empty());
+        assert!(events.spans.is_empty());
+        assert_eq!(events.commits_per_fsync, None);
+    }
+}
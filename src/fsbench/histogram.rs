@@ -0,0 +1,106 @@
+// Log-linear histogram in the style of HdrHistogram.
+//
+// Values below 2^SUB_BUCKET_BITS are counted exactly. Above that, every power-of-two range
+// [2^e, 2^(e+1)) is split into 2^(SUB_BUCKET_BITS - 1) equally sized sub-buckets, so any
+// recorded value is off by less than 1 / 2^(SUB_BUCKET_BITS - 1) (< 0.8%) of its magnitude.
+// The bucket array has a fixed size, so memory use does not grow with the number of samples.
+
+const SUB_BUCKET_BITS: u32 = 8;
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+const SUB_BUCKET_HALF: usize = SUB_BUCKET_COUNT / 2;
+// Enough buckets to cover every u64
+const NUM_BUCKETS: usize = SUB_BUCKET_COUNT + (64 - SUB_BUCKET_BITS as usize) * SUB_BUCKET_HALF;
+
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total_count: u64,
+    max: u64,
+}
+
+fn index_of(value: u64) -> usize {
+    if value < SUB_BUCKET_COUNT as u64 {
+        value as usize
+    } else {
+        let exponent = 63 - value.leading_zeros();
+        let shift = exponent - (SUB_BUCKET_BITS - 1);
+        let sub_bucket = (value >> shift) as usize;
+        SUB_BUCKET_COUNT + (shift as usize - 1) * SUB_BUCKET_HALF + (sub_bucket - SUB_BUCKET_HALF)
+    }
+}
+
+// Returns the [lower, upper) range of values counted by the bucket at `index`
+fn range_of(index: usize) -> (u64, u64) {
+    if index < SUB_BUCKET_COUNT {
+        (index as u64, index as u64 + 1)
+    } else {
+        let offset = index - SUB_BUCKET_COUNT;
+        let shift = (offset / SUB_BUCKET_HALF + 1) as u32;
+        let sub_bucket = (offset % SUB_BUCKET_HALF + SUB_BUCKET_HALF) as u64;
+        let lower = sub_bucket << shift;
+        (lower, lower.saturating_add(1 << shift))
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; NUM_BUCKETS],
+            total_count: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[index_of(value)] += 1;
+        self.total_count += 1;
+        if value > self.max {
+            self.max = value;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other_count;
+        }
+        self.total_count += other.total_count;
+        if other.max > self.max {
+            self.max = other.max;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total_count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    // Nearest-rank percentile, `percentile` in [0, 100]. Reports the largest value that
+    // falls into the same bucket as the exact answer, clamped to the largest recorded value.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.total_count == 0 {
+            return 0;
+        }
+        let rank = ((percentile / 100.0) * self.total_count as f64).ceil() as u64;
+        let rank = rank.max(1).min(self.total_count);
+        let mut seen: u64 = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank {
+                let (_, upper) = range_of(index);
+                return (upper - 1).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    // Non-empty buckets as (lower, upper, count), with values in [lower, upper)
+    pub fn buckets<'a>(&'a self) -> impl Iterator<Item = (u64, u64, u64)> + 'a {
+        self.counts.iter().enumerate().filter(|&(_, count)| *count > 0).map(|(index, count)| {
+            let (lower, upper) = range_of(index);
+            (lower, upper, *count)
+        })
+    }
+}
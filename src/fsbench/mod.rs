@@ -1,8 +1,14 @@
 use super::nix;
 use super::nix::libc;
 pub mod blktrace;
+pub mod diskstats;
 pub mod fileset;
+pub mod ftrace;
+pub mod histogram;
 pub mod operation;
+pub mod rusage;
 pub mod statistics;
+pub mod taskstats;
+pub mod tracer;
 pub mod util;
 pub mod mount;
@@ -1,6 +1,8 @@
 use super::libc;
 use super::nix;
+use super::rusage;
 use super::statistics::Stats;
+use super::taskstats;
 use super::util;
 use nix::fcntl::OFlag;
 use nix::sys::stat::Mode;
@@ -20,24 +22,6 @@ pub struct Open {
     stats: RwLock<Stats>,
 }
 
-fn get_iowait() -> usize {
-    use std::fs::File;
-    use std::io::Read;
-    use std::str;
-    let f = File::open("/proc/stat").expect("failed to open /proc/stats");
-    let split_string = vec![String::new()];
-    let first_line_bytes = f.bytes().take_while(|c| {
-        match c {
-            &Ok(ref ch) => *ch != '\n' as u8,
-            &_ => false,
-        }
-    }).map(|c| c.unwrap()).collect::<Vec<u8>>();
-    let first_line = str::from_utf8(&first_line_bytes).expect("failed to parse bytes as utf8");
-    let mut split = first_line.split_whitespace();
-    let iowait: Option<usize> = split.nth(5).and_then(|s| s.parse().ok()); // 6th column is iowait
-    iowait.expect("failed to read iowait")
-}
-
 impl Open {
     pub fn new() -> Open {
         Open {
@@ -47,12 +31,20 @@ impl Open {
 
     pub fn run<P: ?Sized + nix::NixPath>(&mut self, path: &P, oflag: OFlag, mode: Mode) -> nix::Result<RawFd> {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         match nix::fcntl::open(path, oflag, mode) {
             Ok(fd) => {
                 let elapsed = start.elapsed();
-                stats.record(elapsed, 0, get_iowait() - start_iowait);
+                stats.record(
+                    start_time,
+                    elapsed,
+                    0,
+                    taskstats::io_delay_nanos().saturating_sub(start_iowait),
+                    rusage::since(start_usage),
+                );
                 Ok(fd)
             }
             Err(e) => Err(e),
@@ -79,12 +71,20 @@ impl Close {
 
     pub fn run(&mut self, fd: RawFd) -> nix::Result<()> {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         match nix::unistd::close(fd) {
             Ok(()) => {
                 let elapsed = start.elapsed();
-                stats.record(elapsed, 0, get_iowait() - start_iowait);
+                stats.record(
+                    start_time,
+                    elapsed,
+                    0,
+                    taskstats::io_delay_nanos().saturating_sub(start_iowait),
+                    rusage::since(start_usage),
+                );
                 Ok(())
             }
             Err(e) => Err(e),
@@ -111,12 +111,20 @@ impl Fsync {
 
     pub fn run(&mut self, fd: RawFd) -> nix::Result<()> {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         match nix::unistd::fsync(fd) {
             Ok(()) => {
                 let elapsed = start.elapsed();
-                stats.record(elapsed, 0, get_iowait() - start_iowait);
+                stats.record(
+                    start_time,
+                    elapsed,
+                    0,
+                    taskstats::io_delay_nanos().saturating_sub(start_iowait),
+                    rusage::since(start_usage),
+                );
                 Ok(())
             }
             Err(e) => Err(e),
@@ -143,11 +151,19 @@ impl Sync {
 
     pub fn run(&mut self) {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         util::sync_all();
         let elapsed = start.elapsed();
-        stats.record(elapsed, 0, get_iowait() - start_iowait);
+        stats.record(
+            start_time,
+            elapsed,
+            0,
+            taskstats::io_delay_nanos().saturating_sub(start_iowait),
+            rusage::since(start_usage),
+        );
     }
 }
 
@@ -170,12 +186,20 @@ impl Read {
 
     pub fn run(&mut self, fd: RawFd, buf: &mut [u8]) -> nix::Result<usize> {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         match nix::unistd::read(fd, buf) {
             Ok(bytes_read) => {
                 let elapsed = start.elapsed();
-                stats.record(elapsed, bytes_read, get_iowait() - start_iowait);
+                stats.record(
+                    start_time,
+                    elapsed,
+                    bytes_read,
+                    taskstats::io_delay_nanos().saturating_sub(start_iowait),
+                    rusage::since(start_usage),
+                );
                 Ok(bytes_read)
             }
             Err(e) => Err(e),
@@ -202,12 +226,20 @@ impl Write {
 
     pub fn run(&mut self, fd: RawFd, buf: &[u8]) -> nix::Result<usize> {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         match nix::unistd::write(fd, buf) {
             Ok(bytes_written) => {
                 let elapsed = start.elapsed();
-                stats.record(elapsed, bytes_written, get_iowait() - start_iowait);
+                stats.record(
+                    start_time,
+                    elapsed,
+                    bytes_written,
+                    taskstats::io_delay_nanos().saturating_sub(start_iowait),
+                    rusage::since(start_usage),
+                );
                 Ok(bytes_written)
             }
             Err(e) => Err(e),
@@ -234,12 +266,20 @@ impl Unlink {
 
     pub fn run<P: ?Sized + nix::NixPath>(&mut self, path: &P) -> nix::Result<()> {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         match nix::unistd::unlink(path) {
             Ok(()) => {
                 let elapsed = start.elapsed();
-                stats.record(elapsed, 0, get_iowait() - start_iowait);
+                stats.record(
+                    start_time,
+                    elapsed,
+                    0,
+                    taskstats::io_delay_nanos().saturating_sub(start_iowait),
+                    rusage::since(start_usage),
+                );
                 Ok(())
             }
             Err(e) => Err(e),
@@ -266,12 +306,20 @@ impl Rename {
 
     pub fn run<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from_path: &P, to_path: &Q) -> io::Result<()> {
         let mut stats = self.stats.write().unwrap();
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         match fs::rename(from_path, to_path) {
             Ok(()) => {
                 let elapsed = start.elapsed();
-                stats.record(elapsed, 0, get_iowait() - start_iowait);
+                stats.record(
+                    start_time,
+                    elapsed,
+                    0,
+                    taskstats::io_delay_nanos().saturating_sub(start_iowait),
+                    rusage::since(start_usage),
+                );
                 Ok(())
             }
             Err(e) => Err(e),
@@ -299,7 +347,9 @@ impl ReadDir {
     pub fn run<P: AsRef<Path> + ::std::fmt::Debug>(&mut self, path: P) -> io::Result<()> {
         let mut stats = self.stats.write().unwrap();
         let readdir = fs::read_dir(path)?;
-        let start_iowait = get_iowait();
+        let start_usage = rusage::sample();
+        let start_iowait = taskstats::io_delay_nanos();
+        let start_time = util::monotonic_nanos();
         let start = Instant::now();
         let mut bytes = 0;
         for entry in readdir {
@@ -309,7 +359,13 @@ impl ReadDir {
             }
         }
         let elapsed = start.elapsed();
-        stats.record(elapsed, 0, get_iowait() - start_iowait);
+        stats.record(
+            start_time,
+            elapsed,
+            0,
+            taskstats::io_delay_nanos().saturating_sub(start_iowait),
+            rusage::since(start_usage),
+        );
         Ok(())
     }
 }
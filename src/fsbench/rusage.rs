@@ -0,0 +1,117 @@
+// Per-thread resource usage around a single operation: context switches and major faults from
+// getrusage(RUSAGE_THREAD), and storage IO from /proc/thread-self/io. A voluntary context switch
+// means the operation slept; together with its iowait that tells whether it slept on IO or on
+// something else, e.g. a lock.
+use super::libc;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::io::{Seek, SeekFrom};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Whether operations sample their resource usage. Sampling costs a syscall and a procfs read on
+// each side of every operation, so it's off unless asked for on the command line.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct ResourceUsage {
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub major_faults: u64,
+    // Bytes this thread caused to be read from / written to storage, see
+    // Documentation/filesystems/proc.txt
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    // Dirty page cache this thread truncated before it was written back
+    pub cancelled_write_bytes: u64,
+}
+
+thread_local! {
+    // /proc/thread-self resolves to the thread that opened it, so each thread keeps its own
+    static THREAD_IO: RefCell<Option<File>> = RefCell::new(File::open("/proc/thread-self/io").ok());
+}
+
+fn add_thread_io(usage: &mut ResourceUsage) {
+    THREAD_IO.with(|file| {
+        let mut file = file.borrow_mut();
+        let file = match *file {
+            Some(ref mut file) => file,
+            None => return,
+        };
+        let mut contents = String::new();
+        if file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut contents)).is_err() {
+            return;
+        }
+        for line in contents.lines() {
+            let mut split = line.split(':');
+            let name = split.next();
+            let value = match split.next().and_then(|v| v.trim().parse().ok()) {
+                Some(value) => value,
+                None => continue,
+            };
+            match name {
+                Some("read_bytes") => usage.read_bytes = value,
+                Some("write_bytes") => usage.write_bytes = value,
+                Some("cancelled_write_bytes") => usage.cancelled_write_bytes = value,
+                _ => (),
+            }
+        }
+    });
+}
+
+impl ResourceUsage {
+    // Counters of the calling thread since it started
+    fn now() -> ResourceUsage {
+        let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+        unsafe {
+            libc::getrusage(libc::RUSAGE_THREAD, &mut rusage);
+        }
+        let mut usage = ResourceUsage {
+            voluntary_switches: rusage.ru_nvcsw as u64,
+            involuntary_switches: rusage.ru_nivcsw as u64,
+            major_faults: rusage.ru_majflt as u64,
+            ..Default::default()
+        };
+        add_thread_io(&mut usage);
+        usage
+    }
+
+    fn since(&self, start: &ResourceUsage) -> ResourceUsage {
+        ResourceUsage {
+            voluntary_switches: self.voluntary_switches.saturating_sub(start.voluntary_switches),
+            involuntary_switches: self.involuntary_switches.saturating_sub(start.involuntary_switches),
+            major_faults: self.major_faults.saturating_sub(start.major_faults),
+            read_bytes: self.read_bytes.saturating_sub(start.read_bytes),
+            write_bytes: self.write_bytes.saturating_sub(start.write_bytes),
+            cancelled_write_bytes: self.cancelled_write_bytes.saturating_sub(start.cancelled_write_bytes),
+        }
+    }
+
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+        self.major_faults += other.major_faults;
+        self.read_bytes += other.read_bytes;
+        self.write_bytes += other.write_bytes;
+        self.cancelled_write_bytes += other.cancelled_write_bytes;
+    }
+}
+
+// Taken before an operation; None if sampling is disabled
+pub fn sample() -> Option<ResourceUsage> {
+    if ENABLED.load(Ordering::Relaxed) {
+        Some(ResourceUsage::now())
+    } else {
+        None
+    }
+}
+
+// Usage since `start`, as returned by `sample`
+pub fn since(start: Option<ResourceUsage>) -> Option<ResourceUsage> {
+    start.map(|start| ResourceUsage::now().since(&start))
+}
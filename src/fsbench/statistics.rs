@@ -1,55 +1,479 @@
+use super::histogram::Histogram;
+use super::rusage::ResourceUsage;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-#[derive(Clone, Serialize)]
+// Whether newly created Stats keep every sample or only a fixed-size histogram.
+// Set once from the command line before any benchmark runs.
+static STREAMING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_streaming(streaming: bool) {
+    STREAMING.store(streaming, Ordering::SeqCst);
+}
+
+#[derive(Clone)]
 pub struct Stats {
-    latency: Vec<Duration>,
-    bytes: Vec<usize>,
-    iowait: Vec<usize>,
+    samples: Samples,
+    // CLOCK_MONOTONIC time (ns) the serialized start times are relative to, see with_origin
+    origin: u64,
+}
+
+#[derive(Clone)]
+enum Samples {
+    // Every recorded operation is kept
+    Raw {
+        // CLOCK_MONOTONIC time (ns) at which each operation started
+        start: Vec<u64>,
+        latency: Vec<Duration>,
+        bytes: Vec<usize>,
+        // Time (ns) the operating thread spent waiting for block IO, see taskstats::io_delay_nanos
+        iowait: Vec<usize>,
+        // One per operation like the other samples; None where the operation wasn't sampled
+        usage: Vec<Option<ResourceUsage>>,
+    },
+    // Constant memory: latencies go into a histogram, everything else into running totals
+    Streaming(StreamingStats),
+}
+
+#[derive(Clone)]
+struct StreamingStats {
+    latency: Histogram,
+    total_latency_nanos: u64,
+    // Sum of squared latencies (ns^2) for the standard deviation
+    latency_sum_squares: f64,
+    total_bytes: usize,
+    // ns
+    total_iowait: usize,
+    usage: UsageSummary,
+}
+
+// Resource usage of the operations that sampled it, see rusage::set_enabled
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct UsageSummary {
+    pub operations: usize,
+    pub total: ResourceUsage,
+    // Operations that slept, i.e. had at least one voluntary context switch
+    pub blocked: usize,
+    // Operations that slept and spent some of that time waiting for block IO. The rest of the
+    // blocked operations slept on something else, e.g. a lock or a journal commit by another thread.
+    pub blocked_on_io: usize,
+}
+
+impl UsageSummary {
+    fn record(&mut self, usage: &ResourceUsage, iowait: usize) {
+        self.operations += 1;
+        self.total.add(usage);
+        if usage.voluntary_switches > 0 {
+            self.blocked += 1;
+            if iowait > 0 {
+                self.blocked_on_io += 1;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &UsageSummary) {
+        self.operations += other.operations;
+        self.total.add(&other.total);
+        self.blocked += other.blocked;
+        self.blocked_on_io += other.blocked_on_io;
+    }
+}
+
+impl StreamingStats {
+    fn new() -> StreamingStats {
+        StreamingStats {
+            latency: Histogram::new(),
+            total_latency_nanos: 0,
+            latency_sum_squares: 0.0,
+            total_bytes: 0,
+            total_iowait: 0,
+            usage: UsageSummary::default(),
+        }
+    }
+
+    fn record(&mut self, latency: Duration, bytes: usize, iowait: usize, usage: Option<&ResourceUsage>) {
+        let nanos = duration_to_nanos(latency);
+        self.latency.record(nanos);
+        self.total_latency_nanos += nanos;
+        self.latency_sum_squares += (nanos as f64) * (nanos as f64);
+        self.total_bytes += bytes;
+        self.total_iowait += iowait;
+        if let Some(usage) = usage {
+            self.usage.record(usage, iowait);
+        }
+    }
+
+    fn merge(&mut self, other: &StreamingStats) {
+        self.latency.merge(&other.latency);
+        self.total_latency_nanos += other.total_latency_nanos;
+        self.latency_sum_squares += other.latency_sum_squares;
+        self.total_bytes += other.total_bytes;
+        self.total_iowait += other.total_iowait;
+        self.usage.merge(&other.usage);
+    }
+}
+
+// Converts a Duration to fractional seconds
+pub fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + (duration.subsec_nanos() as f64 / 1_000_000_000 as f64)
 }
 
 impl Stats {
     pub fn new() -> Stats {
+        if STREAMING.load(Ordering::SeqCst) {
+            Stats::streaming()
+        } else {
+            Stats::raw()
+        }
+    }
+
+    pub fn raw() -> Stats {
+        Stats {
+            samples: Samples::Raw {
+                start: Vec::new(),
+                latency: Vec::new(),
+                bytes: Vec::new(),
+                iowait: Vec::new(),
+                usage: Vec::new(),
+            },
+            origin: 0,
+        }
+    }
+
+    pub fn streaming() -> Stats {
         Stats {
-            latency: Vec::new(),
-            bytes: Vec::new(),
-            iowait: Vec::new(),
+            samples: Samples::Streaming(StreamingStats::new()),
+            origin: 0,
         }
     }
 
+    // Serializes the start times as offsets (ns) from `origin`, usually Trace::start_time, i.e.
+    // when the measured phase started. Operations of the unmeasured setup have negative offsets.
+    pub fn with_origin(mut self, origin: u64) -> Stats {
+        self.origin = origin;
+        self
+    }
+
     pub fn total_latency(&self) -> Duration {
-        return self.latency.iter().fold(Duration::new(0, 0), |acc, s| acc + *s);
+        match self.samples {
+            Samples::Raw { ref latency, .. } => latency.iter().fold(Duration::new(0, 0), |acc, s| acc + *s),
+            Samples::Streaming(ref s) => Duration::from_nanos(s.total_latency_nanos),
+        }
     }
 
     pub fn total_bytes(&self) -> usize {
-        return self.bytes.iter().fold(0, |acc, s| acc + s);
+        match self.samples {
+            Samples::Raw { ref bytes, .. } => bytes.iter().fold(0, |acc, s| acc + s),
+            Samples::Streaming(ref s) => s.total_bytes,
+        }
     }
 
     pub fn total_iowait(&self) -> usize {
-        return self.iowait.iter().fold(0, |acc, s| acc + s);
+        match self.samples {
+            Samples::Raw { ref iowait, .. } => iowait.iter().fold(0, |acc, s| acc + s),
+            Samples::Streaming(ref s) => s.total_iowait,
+        }
     }
 
-    pub fn record(&mut self, latency: Duration, bytes: usize, iowait: usize) {
-        self.latency.push(latency);
-        self.bytes.push(bytes);
-        self.iowait.push(iowait);
+    // Resource usage summed over the operations that sampled it, None if none did
+    pub fn usage_summary(&self) -> Option<UsageSummary> {
+        let summary = match self.samples {
+            Samples::Raw {
+                ref iowait, ref usage, ..
+            } => {
+                let mut summary = UsageSummary::default();
+                for (usage, iowait) in usage.iter().zip(iowait.iter()) {
+                    if let Some(ref usage) = *usage {
+                        summary.record(usage, *iowait);
+                    }
+                }
+                summary
+            }
+            Samples::Streaming(ref s) => s.usage,
+        };
+        if summary.operations > 0 {
+            Some(summary)
+        } else {
+            None
+        }
+    }
+
+    // `start` is the CLOCK_MONOTONIC time (ns) at which the operation started, see util::monotonic_nanos.
+    // `usage` is the operation's resource usage, see rusage::since
+    pub fn record(&mut self, start: u64, latency: Duration, bytes: usize, iowait: usize, usage: Option<ResourceUsage>) {
+        match self.samples {
+            Samples::Raw {
+                start: ref mut starts,
+                latency: ref mut latencies,
+                bytes: ref mut byte_counts,
+                iowait: ref mut iowaits,
+                usage: ref mut usages,
+            } => {
+                starts.push(start);
+                latencies.push(latency);
+                byte_counts.push(bytes);
+                iowaits.push(iowait);
+                usages.push(usage);
+            }
+            Samples::Streaming(ref mut s) => s.record(latency, bytes, iowait, usage.as_ref()),
+        }
     }
 
     pub fn num_ops(&self) -> usize {
-        self.bytes.len()
+        match self.samples {
+            Samples::Raw { ref bytes, .. } => bytes.len(),
+            Samples::Streaming(ref s) => s.latency.count() as usize,
+        }
+    }
+
+    pub fn latency_stddev(&self) -> Duration {
+        let n = self.num_ops() as f64;
+        if n < 2.0 {
+            return Duration::new(0, 0);
+        }
+        let variance = match self.samples {
+            Samples::Raw { ref latency, .. } => {
+                let mean = latency.iter().fold(0.0, |acc, l| acc + duration_to_nanos(*l) as f64) / n;
+                latency.iter().fold(0.0, |acc, l| {
+                    let d = duration_to_nanos(*l) as f64 - mean;
+                    acc + d * d
+                }) / (n - 1.0)
+            }
+            Samples::Streaming(ref s) => {
+                let sum = s.total_latency_nanos as f64;
+                ((s.latency_sum_squares - sum * sum / n) / (n - 1.0)).max(0.0)
+            }
+        };
+        Duration::from_nanos(variance.sqrt() as u64)
+    }
+
+    // Histogram of latencies with power-of-two nanosecond buckets. Empty buckets are omitted.
+    pub fn latency_histogram(&self) -> Vec<HistogramBucket> {
+        let mut counts: Vec<usize> = Vec::new();
+        {
+            let mut add = |nanos: u64, count: usize| {
+                let bucket = log2_bucket(nanos);
+                if counts.len() <= bucket {
+                    counts.resize(bucket + 1, 0);
+                }
+                counts[bucket] += count;
+            };
+            match self.samples {
+                Samples::Raw { ref latency, .. } => for l in latency {
+                    add(duration_to_nanos(*l), 1);
+                },
+                // Histogram buckets never straddle a power of two, so this regrouping is exact
+                Samples::Streaming(ref s) => for (lower, _, count) in s.latency.buckets() {
+                    add(lower, count as usize);
+                },
+            }
+        }
+        counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, count)| *count > 0)
+            .map(|(bucket, count)| HistogramBucket::for_log2_bucket(bucket, *count))
+            .collect()
+    }
+
+    pub fn latency_summary(&self) -> LatencySummary {
+        let num_ops = self.num_ops();
+        let mean = self.total_latency().checked_div(num_ops as u32).unwrap_or(Duration::new(0, 0));
+        let stddev = self.latency_stddev();
+        let histogram = self.latency_histogram();
+        match self.samples {
+            Samples::Raw { ref latency, .. } => {
+                let mut sorted = latency.clone();
+                sorted.sort();
+                LatencySummary {
+                    mean: mean,
+                    stddev: stddev,
+                    p50: percentile_of_sorted(&sorted, 50.0),
+                    p90: percentile_of_sorted(&sorted, 90.0),
+                    p99: percentile_of_sorted(&sorted, 99.0),
+                    p999: percentile_of_sorted(&sorted, 99.9),
+                    max: sorted.last().cloned().unwrap_or(Duration::new(0, 0)),
+                    histogram: histogram,
+                }
+            }
+            Samples::Streaming(ref s) => LatencySummary {
+                mean: mean,
+                stddev: stddev,
+                p50: Duration::from_nanos(s.latency.percentile(50.0)),
+                p90: Duration::from_nanos(s.latency.percentile(90.0)),
+                p99: Duration::from_nanos(s.latency.percentile(99.0)),
+                p999: Duration::from_nanos(s.latency.percentile(99.9)),
+                max: Duration::from_nanos(s.latency.max()),
+                histogram: histogram,
+            },
+        }
     }
 
     pub fn ops_per_second(&self) -> f64 {
         let total_latency = self.total_latency();
         (self.num_ops() as f64) / (total_latency.as_secs() as f64 + (total_latency.subsec_nanos() as f64 / 1_000_000_000 as f64))
     }
+
+    // Groups the operations that started in [origin, origin + window * num_windows) into
+    // fixed-size windows by start time. `origin` is a CLOCK_MONOTONIC time in ns.
+    // None for streaming stats, which don't keep per-operation start times.
+    pub fn windows(&self, origin: u64, window: Duration, num_windows: usize) -> Option<Vec<StatsWindow>> {
+        let (start, latency) = match self.samples {
+            Samples::Raw {
+                ref start, ref latency, ..
+            } => (start, latency),
+            Samples::Streaming(_) => return None,
+        };
+        let mut windows: Vec<StatsWindow> = (0..num_windows).map(|_| StatsWindow::default()).collect();
+        let window_nanos = duration_to_nanos(window);
+        for (start, latency) in start.iter().zip(latency.iter()) {
+            if *start < origin {
+                continue;
+            }
+            let index = ((*start - origin) / window_nanos) as usize;
+            if index >= num_windows {
+                continue;
+            }
+            let latency_nanos = duration_to_nanos(*latency);
+            let w = &mut windows[index];
+            w.ops += 1;
+            w.total_latency_nanos += latency_nanos;
+            w.max_latency_nanos = w.max_latency_nanos.max(latency_nanos);
+        }
+        Some(windows)
+    }
+
+    // Converts raw samples into the streaming representation
+    fn into_streaming(self) -> StreamingStats {
+        match self.samples {
+            Samples::Raw {
+                latency,
+                bytes,
+                iowait,
+                usage,
+                ..
+            } => {
+                let mut s = StreamingStats::new();
+                for (((l, b), i), u) in latency.into_iter().zip(bytes.into_iter()).zip(iowait.into_iter()).zip(usage.iter()) {
+                    s.record(l, b, i, u.as_ref());
+                }
+                s
+            }
+            Samples::Streaming(s) => s,
+        }
+    }
+}
+
+pub fn duration_to_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+// Index of the power-of-two bucket containing `nanos`: bucket i holds [2^i, 2^(i+1)), bucket 0 also holds 0
+fn log2_bucket(nanos: u64) -> usize {
+    if nanos == 0 {
+        0
+    } else {
+        63 - nanos.leading_zeros() as usize
+    }
+}
+
+fn percentile_of_sorted(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::new(0, 0);
+    }
+    let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+// Operations that started within one time-series window, see Stats::windows
+#[derive(Clone, Default)]
+pub struct StatsWindow {
+    pub ops: usize,
+    pub total_latency_nanos: u64,
+    pub max_latency_nanos: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct HistogramBucket {
+    // Bucket covers latencies in [lower, upper) nanoseconds
+    pub lower: u64,
+    pub upper: u64,
+    pub count: usize,
+}
+
+impl HistogramBucket {
+    fn for_log2_bucket(bucket: usize, count: usize) -> HistogramBucket {
+        HistogramBucket {
+            lower: if bucket == 0 { 0 } else { 1 << bucket },
+            upper: if bucket >= 63 { ::std::u64::MAX } else { 1 << (bucket + 1) },
+            count: count,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct LatencySummary {
+    pub mean: Duration,
+    pub stddev: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+// The raw samples are written out as before, followed by the latency summary so
+// consumers of open.json, fsync.json, etc. don't have to recompute percentiles.
+// Streaming stats have no raw samples, so only their totals are written.
+impl Serialize for Stats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.samples {
+            Samples::Raw {
+                ref start,
+                ref latency,
+                ref bytes,
+                ref iowait,
+                ref usage,
+            } => {
+                let mut state = serializer.serialize_struct("Stats", 8)?;
+                let origin = self.origin;
+                let offsets: Vec<i64> = start.iter().map(|&start| start as i64 - origin as i64).collect();
+                state.serialize_field("origin", &origin)?;
+                state.serialize_field("start", &offsets)?;
+                state.serialize_field("latency", latency)?;
+                state.serialize_field("bytes", bytes)?;
+                state.serialize_field("iowait", iowait)?;
+                if usage.iter().any(Option::is_some) {
+                    state.serialize_field("usage", usage)?;
+                    state.serialize_field("usage_summary", &self.usage_summary())?;
+                }
+                state.serialize_field("summary", &self.latency_summary())?;
+                state.end()
+            }
+            Samples::Streaming(ref s) => {
+                let mut state = serializer.serialize_struct("Stats", 6)?;
+                state.serialize_field("num_ops", &self.num_ops())?;
+                state.serialize_field("total_latency", &self.total_latency())?;
+                state.serialize_field("total_bytes", &s.total_bytes)?;
+                state.serialize_field("total_iowait", &s.total_iowait)?;
+                if s.usage.operations > 0 {
+                    state.serialize_field("usage_summary", &s.usage)?;
+                }
+                state.serialize_field("summary", &self.latency_summary())?;
+                state.end()
+            }
+        }
+    }
 }
 
 impl fmt::Display for Stats {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let total_latency = self.total_latency();
         let total_bytes = self.total_bytes();
-        let num_ops = self.bytes.len();
+        let num_ops = self.num_ops();
         let avg_latency = match total_latency.checked_div(num_ops as u32) {
             Some(quotient) => format!("{}.{:09}", quotient.as_secs(), quotient.subsec_nanos()),
             None => String::from("(inf)"),
@@ -63,11 +487,28 @@ impl fmt::Display for Stats {
             total_latency.subsec_nanos()
         )?;
         write!(f, " - Average latency = {}\n", avg_latency)?;
+        let summary = self.latency_summary();
+        write!(
+            f,
+            " - Latency p50 = {:?}, p90 = {:?}, p99 = {:?}, p99.9 = {:?}, max = {:?}, stddev = {:?}\n",
+            summary.p50, summary.p90, summary.p99, summary.p999, summary.max, summary.stddev
+        )?;
         write!(f, " - Bytes/Operation = {}\n", (total_bytes as f64) / (num_ops as f64))?;
 
         let ops_per_second =
             (num_ops as f64) / (total_latency.as_secs() as f64 + (total_latency.subsec_nanos() as f64 / 1_000_000_000 as f64));
         write!(f, " - Operations/Second = {}\n", ops_per_second)?;
+        if let Some(usage) = self.usage_summary() {
+            write!(
+                f,
+                " - Blocked operations = {} ({} on IO), voluntary/involuntary switches = {}/{}, major faults = {}\n",
+                usage.blocked,
+                usage.blocked_on_io,
+                usage.total.voluntary_switches,
+                usage.total.involuntary_switches,
+                usage.total.major_faults
+            )?;
+        }
         Ok(())
     }
 }
@@ -75,10 +516,105 @@ impl fmt::Display for Stats {
 impl ::std::ops::Add for Stats {
     type Output = Stats;
     fn add(self, rhs: Stats) -> Self::Output {
-        Stats {
-            latency: [&self.latency[..], &rhs.latency[..]].concat(),
-            bytes: [&self.bytes[..], &rhs.bytes[..]].concat(),
-            iowait: [&self.iowait[..], &rhs.iowait[..]].concat(),
+        let origin = self.origin;
+        match (self.samples, rhs.samples) {
+            (
+                Samples::Raw {
+                    start: s1,
+                    latency: l1,
+                    bytes: b1,
+                    iowait: i1,
+                    usage: u1,
+                },
+                Samples::Raw {
+                    start: s2,
+                    latency: l2,
+                    bytes: b2,
+                    iowait: i2,
+                    usage: u2,
+                },
+            ) => Stats {
+                samples: Samples::Raw {
+                    start: [&s1[..], &s2[..]].concat(),
+                    latency: [&l1[..], &l2[..]].concat(),
+                    bytes: [&b1[..], &b2[..]].concat(),
+                    iowait: [&i1[..], &i2[..]].concat(),
+                    usage: [&u1[..], &u2[..]].concat(),
+                },
+                origin: origin,
+            },
+            // If either side is streaming, the result is too
+            (lhs, rhs) => {
+                let mut merged = Stats { samples: lhs, origin: origin }.into_streaming();
+                merged.merge(&Stats { samples: rhs, origin: origin }.into_streaming());
+                Stats {
+                    samples: Samples::Streaming(merged),
+                    origin: origin,
+                }
+            }
+        }
+    }
+}
+
+// Two-sided 95% critical values of Student's t distribution for 1..=30 degrees of freedom
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160, 2.145, 2.131,
+    2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+];
+// Normal approximation used past 30 degrees of freedom
+const Z_95: f64 = 1.960;
+
+// Summary statistics of one metric across repeated runs of a benchmark
+#[derive(Clone, Serialize)]
+pub struct Aggregate {
+    pub mean: f64,
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+    // 95% confidence interval of the mean
+    pub ci95_low: f64,
+    pub ci95_high: f64,
+}
+
+impl Aggregate {
+    pub fn from_samples(samples: &[f64]) -> Aggregate {
+        assert!(samples.len() > 0, "cannot aggregate zero samples");
+        let n = samples.len() as f64;
+        let mean = samples.iter().fold(0.0, |acc, s| acc + s) / n;
+        let min = samples.iter().fold(::std::f64::INFINITY, |acc, s| acc.min(*s));
+        let max = samples.iter().fold(::std::f64::NEG_INFINITY, |acc, s| acc.max(*s));
+        // Sample standard deviation; a single run has no spread to speak of
+        let stddev = if samples.len() > 1 {
+            (samples.iter().fold(0.0, |acc, s| acc + (s - mean) * (s - mean)) / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        let degrees_of_freedom = samples.len() - 1;
+        let t = if degrees_of_freedom == 0 {
+            0.0
+        } else if degrees_of_freedom <= T_95.len() {
+            T_95[degrees_of_freedom - 1]
+        } else {
+            Z_95
+        };
+        let half_width = t * stddev / n.sqrt();
+        Aggregate {
+            mean: mean,
+            stddev: stddev,
+            min: min,
+            max: max,
+            ci95_low: mean - half_width,
+            ci95_high: mean + half_width,
         }
     }
 }
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.6} ± {:.6} (min {:.6}, max {:.6}, 95% CI [{:.6}, {:.6}])",
+            self.mean, self.stddev, self.min, self.max, self.ci95_low, self.ci95_high
+        )
+    }
+}
@@ -0,0 +1,205 @@
+// Per-thread block IO delay from the kernel's delay accounting (delayacct), queried over the
+// TASKSTATS generic netlink family. Needs a kernel built with CONFIG_TASK_DELAY_ACCT and delay
+// accounting switched on, either with `delayacct` on the kernel command line or at runtime
+// through /proc/sys/kernel/task_delayacct (see `enable`).
+use super::libc;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::ptr;
+use std::sync::Once;
+
+// include/uapi/linux/netlink.h
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLA_HDRLEN: usize = 4;
+// Nested/byte-order flags in nla_type
+const NLA_TYPE_MASK: u16 = 0x3fff;
+// include/uapi/linux/genetlink.h
+const GENL_HDRLEN: usize = 4;
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+// include/uapi/linux/taskstats.h
+const TASKSTATS_GENL_NAME: &[u8] = b"TASKSTATS\0";
+const TASKSTATS_CMD_GET: u8 = 1;
+const TASKSTATS_CMD_ATTR_PID: u16 = 1;
+const TASKSTATS_TYPE_STATS: u16 = 3;
+const TASKSTATS_TYPE_AGGR_PID: u16 = 4;
+// Offset of blkio_delay_total in struct taskstats, after version, ac_exitcode, ac_flag,
+// ac_nice, cpu_count, cpu_delay_total and blkio_count
+const BLKIO_DELAY_TOTAL_OFFSET: usize = 40;
+
+static WARN_UNAVAILABLE: Once = Once::new();
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+fn gettid() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+// Attributes are (u16 length including the header, u16 type, payload padded to 4 bytes)
+fn attribute(data: &[u8], attr_type: u16) -> Option<&[u8]> {
+    let mut offset = 0;
+    while offset + NLA_HDRLEN <= data.len() {
+        let length = u16::from_ne_bytes([data[offset], data[offset + 1]]) as usize;
+        let kind = u16::from_ne_bytes([data[offset + 2], data[offset + 3]]) & NLA_TYPE_MASK;
+        if length < NLA_HDRLEN || offset + length > data.len() {
+            return None;
+        }
+        if kind == attr_type {
+            return Some(&data[offset + NLA_HDRLEN..offset + length]);
+        }
+        offset += align(length);
+    }
+    None
+}
+
+struct Socket {
+    fd: libc::c_int,
+    family: u16,
+    // Thread id of the thread that owns this socket
+    tid: u32,
+}
+
+impl Socket {
+    fn open() -> io::Result<Socket> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_GENERIC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut socket = Socket {
+            fd: fd,
+            family: GENL_ID_CTRL,
+            tid: gettid(),
+        };
+        // nl_pid 0 lets the kernel pick the port id
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let bound = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let reply = socket.request(GENL_ID_CTRL, CTRL_CMD_GETFAMILY, CTRL_ATTR_FAMILY_NAME, TASKSTATS_GENL_NAME)?;
+        socket.family = match attribute(&reply, CTRL_ATTR_FAMILY_ID) {
+            Some(id) if id.len() >= 2 => u16::from_ne_bytes([id[0], id[1]]),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "no TASKSTATS family id in reply")),
+        };
+        Ok(socket)
+    }
+
+    // Sends a generic netlink request with a single attribute and returns the attributes of
+    // the reply
+    fn request(&self, family: u16, command: u8, attr_type: u16, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let attr_length = NLA_HDRLEN + payload.len();
+        let length = NLMSG_HDRLEN + GENL_HDRLEN + align(attr_length);
+        let mut message = Vec::with_capacity(length);
+        // struct nlmsghdr
+        message.extend_from_slice(&(length as u32).to_ne_bytes());
+        message.extend_from_slice(&family.to_ne_bytes());
+        message.extend_from_slice(&NLM_F_REQUEST.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        // struct genlmsghdr
+        message.extend_from_slice(&[command, 1, 0, 0]);
+        // struct nlattr
+        message.extend_from_slice(&(attr_length as u16).to_ne_bytes());
+        message.extend_from_slice(&attr_type.to_ne_bytes());
+        message.extend_from_slice(payload);
+        message.resize(length, 0);
+
+        let sent = unsafe { libc::send(self.fd, message.as_ptr() as *const libc::c_void, message.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut reply = vec![0u8; 4096];
+        let received = unsafe { libc::recv(self.fd, reply.as_mut_ptr() as *mut libc::c_void, reply.len(), 0) };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let received = received as usize;
+        if received < NLMSG_HDRLEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short netlink reply"));
+        }
+        let kind = u16::from_ne_bytes([reply[4], reply[5]]);
+        if kind == NLMSG_ERROR {
+            // struct nlmsgerr starts with a negative errno
+            let error = i32::from_ne_bytes([reply[16], reply[17], reply[18], reply[19]]);
+            return Err(io::Error::from_raw_os_error(-error));
+        }
+        if received < NLMSG_HDRLEN + GENL_HDRLEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short netlink reply"));
+        }
+        reply.truncate(received);
+        Ok(reply.split_off(NLMSG_HDRLEN + GENL_HDRLEN))
+    }
+
+    fn blkio_delay_total(&self) -> io::Result<u64> {
+        let reply = self.request(self.family, TASKSTATS_CMD_GET, TASKSTATS_CMD_ATTR_PID, &self.tid.to_ne_bytes())?;
+        let stats = attribute(&reply, TASKSTATS_TYPE_AGGR_PID).and_then(|aggregate| attribute(aggregate, TASKSTATS_TYPE_STATS));
+        match stats {
+            Some(stats) if stats.len() >= BLKIO_DELAY_TOTAL_OFFSET + 8 => Ok(unsafe {
+                ptr::read_unaligned(stats[BLKIO_DELAY_TOTAL_OFFSET..].as_ptr() as *const u64)
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "no taskstats in reply")),
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn warn_unavailable(e: &io::Error) {
+    WARN_UNAVAILABLE.call_once(|| warn!("taskstats unavailable, iowait will be reported as 0: {}", e));
+}
+
+thread_local! {
+    // One socket per thread, opened on first use
+    static SOCKET: Option<Socket> = Socket::open().map_err(|e| warn_unavailable(&e)).ok();
+}
+
+// Total time (ns) the calling thread has spent waiting for block IO to complete. Returns 0 if
+// delay accounting isn't available.
+pub fn io_delay_nanos() -> usize {
+    SOCKET.with(|socket| match *socket {
+        Some(ref socket) => match socket.blkio_delay_total() {
+            Ok(nanos) => nanos as usize,
+            Err(e) => {
+                warn_unavailable(&e);
+                0
+            }
+        },
+        None => 0,
+    })
+}
+
+// Turns delay accounting on. Kernels since 5.14 leave it off unless `delayacct` is on the
+// command line, in which case every delay reads as 0.
+pub fn enable() {
+    let enabled = OpenOptions::new()
+        .write(true)
+        .open("/proc/sys/kernel/task_delayacct")
+        .and_then(|mut f| f.write_all(b"1\n"));
+    if let Err(e) = enabled {
+        // Older kernels have no switch and always account delays
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("failed to enable delay accounting: {}", e);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Backends that record the IO a benchmark's measured phase causes. blktrace sees every request
+// but needs debugfs and the blktrace ioctls; the block device stats only give totals but work on
+// any kernel; and without either only the operations' timings are left.
+use super::blktrace::{Blktrace, Trace};
+use super::diskstats::DiskStatsCollector;
+use super::ftrace::Ftrace;
+use super::nix;
+use super::util::{drop_cache, monotonic_nanos};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+
+pub trait IoTracer {
+    // Runs `task` and records the IO it causes. `output_dir` is the benchmark's output
+    // directory, for tracers that write their data while recording. `at_start` runs right before
+    // the task's start time is taken.
+    fn record(&self, output_dir: &Path, at_start: &mut FnMut(), task: &mut FnMut()) -> nix::Result<Trace>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TracerKind {
+    Blktrace,
+    DiskStats,
+    None,
+}
+
+impl FromStr for TracerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blktrace" => Ok(TracerKind::Blktrace),
+            "diskstats" => Ok(TracerKind::DiskStats),
+            "none" => Ok(TracerKind::None),
+            _ => Err(format!("unknown IO tracer `{}` (expected blktrace, diskstats or none)", s)),
+        }
+    }
+}
+
+// Runs `task` with a cold page cache like Blktrace::record_with, but without tracing. The cache
+// is flushed afterwards so its writeback reaches the device before the recording ends.
+fn record_untraced(at_start: &mut FnMut(), task: &mut FnMut()) -> Trace {
+    drop_cache();
+    at_start();
+    let start_time = monotonic_nanos();
+    let start = Instant::now();
+    task();
+    let elapsed = start.elapsed();
+    drop_cache();
+    Trace::untraced(start_time, elapsed)
+}
+
+// Records with `record` and adds the block device stats from right before the task starts (i.e.
+// after any warmup) until the recording, including its cooldown and final flush, is over.
+// `record` gets a callback to run before it takes the task's start time, which runs `at_start`
+// first, and the task.
+fn record_with_disk_stats<R>(disk_stats: &DiskStatsCollector, at_start: &mut FnMut(), task: &mut FnMut(), record: R) -> nix::Result<Trace>
+where
+    R: FnOnce(&mut FnMut(), &mut FnMut()) -> nix::Result<Trace>,
+{
+    let mut before = None;
+    let mut trace = record(
+        &mut || {
+            at_start();
+            before = Some(disk_stats.snapshot())
+        },
+        task,
+    )?;
+    match before.map(|before| before.and_then(|before| disk_stats.since(&before))) {
+        Some(Ok(stats)) => trace.set_disk_stats(stats),
+        Some(Err(e)) => warn!("failed to read block device stats: {}", e),
+        None => (),
+    }
+    Ok(trace)
+}
+
+// Traces every request with blktrace and cross-checks the result with the block device stats
+pub struct BlktraceTracer {
+    blktrace: Blktrace,
+    disk_stats: DiskStatsCollector,
+    // Write traces to the benchmark's output directory while recording instead of buffering them
+    stream_traces: bool,
+}
+
+impl BlktraceTracer {
+    pub fn new(blktrace: Blktrace, disk_stats: DiskStatsCollector, stream_traces: bool) -> Self {
+        BlktraceTracer {
+            blktrace: blktrace,
+            disk_stats: disk_stats,
+            stream_traces: stream_traces,
+        }
+    }
+}
+
+impl IoTracer for BlktraceTracer {
+    // Streamed traces end up in the same place Benchmark::export puts them
+    fn record(&self, output_dir: &Path, at_start: &mut FnMut(), task: &mut FnMut()) -> nix::Result<Trace> {
+        record_with_disk_stats(&self.disk_stats, at_start, task, |at_start, task| {
+            if self.stream_traces {
+                self.blktrace.record_to(output_dir, "blktrace", || at_start(), || task())
+            } else {
+                self.blktrace.record_with(|| at_start(), || task())
+            }
+        })
+    }
+}
+
+// Only reads the block device stats, for kernels without CONFIG_BLK_DEV_IO_TRACE or debugfs
+pub struct DiskStatsTracer {
+    disk_stats: DiskStatsCollector,
+}
+
+impl DiskStatsTracer {
+    pub fn new(disk_stats: DiskStatsCollector) -> Self {
+        DiskStatsTracer { disk_stats: disk_stats }
+    }
+}
+
+impl IoTracer for DiskStatsTracer {
+    fn record(&self, _output_dir: &Path, at_start: &mut FnMut(), task: &mut FnMut()) -> nix::Result<Trace> {
+        record_with_disk_stats(&self.disk_stats, at_start, task, |at_start, task| {
+            Ok(record_untraced(at_start, task))
+        })
+    }
+}
+
+// Records nothing but the task's timing, e.g. where neither blktrace nor the block device stats
+// can be read
+pub struct NoopTracer;
+
+impl IoTracer for NoopTracer {
+    fn record(&self, _output_dir: &Path, at_start: &mut FnMut(), task: &mut FnMut()) -> nix::Result<Trace> {
+        Ok(record_untraced(at_start, task))
+    }
+}
+
+// Adds filesystem tracepoints to the recordings of another tracer. Like the block device stats
+// they cover the task and whatever the other tracer records after it; tracing starts before the
+// task's start time is taken so setting up tracefs isn't timed.
+pub struct FtraceTracer {
+    ftrace: Ftrace,
+    inner: Box<IoTracer>,
+}
+
+impl FtraceTracer {
+    pub fn new(ftrace: Ftrace, inner: Box<IoTracer>) -> Self {
+        FtraceTracer {
+            ftrace: ftrace,
+            inner: inner,
+        }
+    }
+}
+
+impl IoTracer for FtraceTracer {
+    fn record(&self, output_dir: &Path, at_start: &mut FnMut(), task: &mut FnMut()) -> nix::Result<Trace> {
+        let mut started = false;
+        let mut trace = {
+            let mut start_ftrace = || {
+                at_start();
+                match self.ftrace.start() {
+                    Ok(()) => started = true,
+                    Err(e) => warn!("failed to start ftrace: {}", e),
+                }
+            };
+            self.inner.record(output_dir, &mut start_ftrace, task)?
+        };
+        if started {
+            match self.ftrace.stop() {
+                Ok(fs_events) => trace.set_fs_events(fs_events),
+                Err(e) => warn!("failed to read ftrace events: {}", e),
+            }
+        }
+        Ok(trace)
+    }
+}
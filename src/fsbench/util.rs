@@ -4,6 +4,7 @@ use std::fs::OpenOptions;
 use std::io;
 use std::io::prelude::*;
 use std::path::Path;
+use std::str::FromStr;
 
 // Recursively constructs a directory tree
 pub fn mkdir<P: AsRef<Path>>(path: P) -> io::Result<()> {
@@ -23,16 +24,33 @@ pub fn mkdir<P: AsRef<Path>>(path: P) -> io::Result<()> {
     }
 }
 
+// Syncs and drops the page cache. Best-effort: where /proc/sys is read-only (e.g. in a
+// container) the caches stay warm, which is logged once rather than stopping the run.
 pub fn drop_cache() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if let Err(e) = try_drop_cache() {
+        if !WARNED.swap(true, Ordering::SeqCst) {
+            warn!("failed to drop caches, results may include cached data: {}", e);
+        }
+    }
+}
+
+fn try_drop_cache() -> io::Result<()> {
     // 'echo 3 >/proc/sys/vm/drop_caches'
     sync_all();
-    let mut drop_cache_file = OpenOptions::new()
-        .write(true)
-        .open("/proc/sys/vm/drop_caches")
-        .expect("failed to open drop_caches but we should be root");
-    drop_cache_file
-        .write_all(b"3\n")
-        .expect("failed to write to drop_caches but we should be root");
+    let mut drop_cache_file = OpenOptions::new().write(true).open("/proc/sys/vm/drop_caches")?;
+    drop_cache_file.write_all(b"3\n")
+}
+
+// Current CLOCK_MONOTONIC time in nanoseconds. This is the clock the kernel uses
+// for blktrace event timestamps, so the two can be compared directly.
+pub fn monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
 }
 
 // Wrapper around unsafe libc::sync
@@ -42,13 +60,31 @@ pub fn sync_all() {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Filesystem {
     Ext2,
     Ext4,
     Ext4NoJournal,
     Xfs,
     Btrfs,
-    F2fs
+    F2fs,
+    // Whatever is mounted at `--mount-path` when no device is given; fsbench neither creates nor
+    // mounts it
+    Mounted,
+}
+
+impl Filesystem {
+    // Every filesystem fsbench knows how to create, in the order they are benchmarked
+    pub fn all() -> Vec<Filesystem> {
+        vec![
+            Filesystem::Ext4,
+            Filesystem::Ext4NoJournal,
+            Filesystem::F2fs,
+            Filesystem::Btrfs,
+            Filesystem::Xfs,
+            Filesystem::Ext2,
+        ]
+    }
 }
 
 pub fn mkfs(device: &str, fs: &Filesystem) {
@@ -129,6 +165,7 @@ pub fn mkfs(device: &str, fs: &Filesystem) {
                     panic!("failed to mkfs.btrfs {}", device);
                 }
         },
+        Filesystem::Mounted => panic!("the filesystem at --mount-path is used as is, not created on {}", device),
     }
 }
 
@@ -141,6 +178,22 @@ impl ToString for Filesystem {
             Filesystem::Xfs => String::from("xfs"),
             Filesystem::Btrfs => String::from("btrfs"),
             Filesystem::F2fs => String::from("f2fs"),
+            Filesystem::Mounted => String::from("mounted"),
+        }
+    }
+}
+
+impl FromStr for Filesystem {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ext2" => Ok(Filesystem::Ext2),
+            "ext4" => Ok(Filesystem::Ext4),
+            "ext4-no-journal" => Ok(Filesystem::Ext4NoJournal),
+            "xfs" => Ok(Filesystem::Xfs),
+            "btrfs" => Ok(Filesystem::Btrfs),
+            "f2fs" => Ok(Filesystem::F2fs),
+            _ => Err(format!("unknown filesystem `{}`", s)),
         }
     }
 }
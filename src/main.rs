@@ -23,62 +23,541 @@ extern crate bitflags;
 mod benchmarks;
 mod fsbench;
 
-use std::time::Duration;
-
 fn main() {
     // Enable backtraces
     ::std::env::set_var("RUST_BACKTRACE", "1");
 
-    use fsbench::blktrace::*;
-    use fsbench::mount::Mount;
-    use fsbench::util::{drop_cache, mkfs, Filesystem};
-    use benchmarks::Config;
-    use std::path::PathBuf;
     setup_logger().expect("failed to setup logger");
     let matches = clap::App::new("Filesystem Benchmark")
         .version("0.1")
         .about("Runs benchmarks on a filesystem")
-        .arg(
-            clap::Arg::with_name("DEVICE")
-                .short("d")
-                .long("device")
-                .help("Block device to run tests on (note: must be unmounted)")
-                .takes_value(true),
-        )
-        .arg(
-            clap::Arg::with_name("OUTPUT")
-                .short("o")
-                .long("output-directory")
-                .help("Output directory (default = './output)")
-                .takes_value(true),
-        )
-        .arg(
-            clap::Arg::with_name("DEBUGFS")
-                .short("k")
-                .long("debugfs-path")
-                .help("debugfs path (default = '/sys/kernel/debug')")
-                .takes_value(true),
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::SubCommand::with_name("run")
+                .about("Runs the selected benchmarks on the selected filesystems")
+                .arg(
+                    clap::Arg::with_name("DEVICE")
+                        .short("d")
+                        .long("device")
+                        .help(
+                            "Block device to run tests on (note: must be unmounted). Without it, the benchmarks run in the \
+                             filesystem already mounted at --mount-path, e.g. tmpfs or NFS (needs --io-tracer diskstats or none)",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("TRACE_DEVICES")
+                        .long("trace-device")
+                        .help("Additional block devices to trace, e.g. an external journal (comma-separated)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true),
+                )
+                .arg(
+                    clap::Arg::with_name("OUTPUT")
+                        .short("o")
+                        .long("output-directory")
+                        .help("Output directory (default = './output)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("DEBUGFS")
+                        .short("k")
+                        .long("debugfs-path")
+                        .help("debugfs path (default = '/sys/kernel/debug')")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("MOUNT_PATH")
+                        .short("m")
+                        .long("mount-path")
+                        .help("where to mount the block device, or the mounted filesystem to use without --device")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("FILESYSTEMS")
+                        .short("f")
+                        .long("fs")
+                        .help("Comma-separated list of filesystems to test (default = all, see `list`)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true),
+                )
+                .arg(
+                    clap::Arg::with_name("BENCHMARKS")
+                        .short("b")
+                        .long("bench")
+                        .help("Comma-separated list of benchmarks to run (default = all, see `list`)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true),
+                )
+                .arg(
+                    clap::Arg::with_name("REPETITIONS")
+                        .short("r")
+                        .long("repetitions")
+                        .help("Number of times to repeat each benchmark (default = 1)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("STREAMING_STATS")
+                        .long("streaming-stats")
+                        .help("Keep per-operation latencies in fixed-size histograms instead of storing every sample"),
+                )
+                .arg(
+                    clap::Arg::with_name("RESOURCE_USAGE")
+                        .long("resource-usage")
+                        .help(
+                            "Record each operation's context switches, major faults and storage IO from getrusage and \
+                             /proc/thread-self/io",
+                        ),
+                )
+                .arg(
+                    clap::Arg::with_name("IO_TRACER")
+                        .long("io-tracer")
+                        .help(
+                            "How to record the IO of each benchmark: `blktrace`, `diskstats` (block device counters only, \
+                             for kernels without blktrace) or `none` (default = blktrace)",
+                        )
+                        .takes_value(true)
+                        .possible_values(&["blktrace", "diskstats", "none"]),
+                )
+                .arg(
+                    clap::Arg::with_name("FTRACE")
+                        .long("ftrace")
+                        .help("Also record filesystem tracepoints (fsyncs, journal commits, writeback) with ftrace"),
+                )
+                .arg(
+                    clap::Arg::with_name("FTRACE_EVENTS")
+                        .long("ftrace-events")
+                        .help(
+                            "Comma-separated list of tracepoints to record with ftrace, e.g. \
+                             `jbd2:jbd2_commit_*,writeback:writeback_start` (implies --ftrace)",
+                        )
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .multiple(true),
+                )
+                .arg(
+                    clap::Arg::with_name("STREAM_TRACES")
+                        .long("stream-traces")
+                        .help("Write blktrace data to the output directory while recording instead of buffering it in memory"),
+                )
+                .arg(
+                    clap::Arg::with_name("ON_TRACE_LOSS")
+                        .long("on-trace-loss")
+                        .help(
+                            "What to do when blktrace drops events: `warn` marks the summary incomplete, `fail` stops, \
+                             `retry` reruns the benchmark with larger trace buffers (default = warn)",
+                        )
+                        .takes_value(true)
+                        .possible_values(&["warn", "fail", "retry"]),
+                )
+                .arg(
+                    clap::Arg::with_name("TRACE_BUFFER_SIZE")
+                        .long("trace-buffer-size")
+                        .help("Size of each blktrace sub-buffer in KiB (default = 512)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("TRACE_BUFFERS")
+                        .long("trace-buffers")
+                        .help("Number of blktrace sub-buffers per CPU (default = 4)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("TRACE_MASK")
+                        .long("trace-mask")
+                        .help("Comma-separated list of blktrace actions to trace, e.g. `write,sync,meta` (default = all)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("TRACE_START_LBA")
+                        .long("trace-start-lba")
+                        .help("Only trace IO at or after this sector")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("TRACE_END_LBA")
+                        .long("trace-end-lba")
+                        .help("Only trace IO before this sector")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("WARMUP")
+                        .long("warmup")
+                        .help("Milliseconds to wait before each measured phase (default = 5000)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("COOLDOWN")
+                        .long("cooldown")
+                        .help("Milliseconds to keep tracing after each measured phase (default = 2000)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("FLUSH_WAIT")
+                        .long("flush-wait")
+                        .help("Milliseconds to keep tracing after the final sync and drop_caches (default = 2000)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("NO_DROP_CACHE")
+                        .long("no-drop-cache")
+                        .help("Don't sync and drop caches at the end of each measured phase"),
+                )
+                .arg(
+                    clap::Arg::with_name("TRACE_PID")
+                        .long("trace-pid")
+                        .help("Only trace IO issued by this process")
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            clap::Arg::with_name("MOUNT_PATH")
-                .short("m")
-                .long("mount-path")
-                .help("where to mount the block device")
-                .takes_value(true),
+        .subcommand(clap::SubCommand::with_name("list").about("Lists the available benchmarks and filesystems"))
+        .subcommand(
+            clap::SubCommand::with_name("analyze")
+                .about("Recomputes IO statistics from blktrace files written by a previous run or by blktrace")
+                .arg(
+                    clap::Arg::with_name("DIRECTORY")
+                        .help("Directory containing <prefix>.<device>.blktrace.N or <device>.blktrace.N files")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("PREFIX")
+                        .short("p")
+                        .long("prefix")
+                        .help("File name prefix of the trace files, e.g. `sda` for sda.blktrace.0 (default = auto-detect)")
+                        .takes_value(true),
+                ),
         )
         .get_matches();
 
-    // we need to be root to use blktrace and mount filesystems
-    let uid = nix::unistd::geteuid();
-    if !uid.is_root() {
-        error!("Need to be root");
-        return;
+    match matches.subcommand() {
+        ("run", Some(run_matches)) => run(run_matches),
+        ("list", Some(_)) => list(),
+        ("analyze", Some(analyze_matches)) => analyze(analyze_matches),
+        _ => unreachable!(),
+    }
+}
+
+fn list() {
+    use fsbench::util::Filesystem;
+    println!("Benchmarks:");
+    for registration in benchmarks::registry() {
+        println!("    {}", registration.name);
+    }
+    println!("Filesystems:");
+    for fstype in Filesystem::all() {
+        println!("    {}", fstype.to_string());
+    }
+}
+
+fn analyze(matches: &clap::ArgMatches) {
+    use fsbench::blktrace::Trace;
+    let directory = matches.value_of("DIRECTORY").expect("No directory specified");
+    let prefix = match matches.value_of("PREFIX") {
+        Some(prefix) => prefix.to_owned(),
+        None => {
+            let prefixes = Trace::find_prefixes(directory).expect("failed to read trace directory");
+            match prefixes.len() {
+                0 => {
+                    error!("no .blktrace.N files found in {}", directory);
+                    return;
+                }
+                1 => prefixes[0].clone(),
+                _ => {
+                    error!("found traces with several prefixes ({}), pick one with --prefix", prefixes.join(", "));
+                    return;
+                }
+            }
+        }
+    };
+    let trace = Trace::load(directory, &prefix).expect("failed to load trace");
+    let io_duration = trace.io_duration();
+    println!("{}/{}.* ({} cpus, {} bytes)", directory, prefix, trace.num_cpus(), trace.total_bytes());
+    println!(" - Completed reads: {} bytes", trace.completed_reads());
+    println!(" - Completed writes: {} bytes", trace.completed_writes());
+    println!(" - IO duration: {}.{:09} s", io_duration.as_secs(), io_duration.subsec_nanos());
+    println!(
+        " - Requests: {} completed, {} matched to their queued bios",
+        trace.num_requests(),
+        trace.matched_requests()
+    );
+    let loss = trace.loss();
+    if !loss.is_complete() {
+        println!(" - Incomplete trace: {} events missing", loss.missing_events());
+        for cpu in &loss.cpus {
+            println!(
+                "   - {} cpu {}: {} missing{}",
+                cpu.device,
+                cpu.cpu,
+                cpu.missing_events,
+                if cpu.corrupt { ", corrupt record" } else { "" }
+            );
+        }
+    }
+    let devices = trace.per_device();
+    if devices.len() > 1 || devices.iter().any(|device| !device.remapped_from.is_empty()) {
+        for device in &devices {
+            println!(
+                " - Device {}: {} requests, {} bytes read, {} bytes written",
+                device.device, device.io_requests, device.reads, device.writes
+            );
+            for source in &device.remapped_from {
+                println!("   - remapped from {}: {} requests, {} bytes", source.device, source.requests, source.bytes);
+            }
+        }
+    }
+    // A reloaded trace doesn't know which pids belonged to the benchmark, so there is no
+    // synchronous/asynchronous split here
+    for process in trace.io_by_process().processes {
+        println!(
+            " - Process {} [{}]: {} requests, {} bytes read, {} bytes written",
+            process.pid, process.command, process.requests, process.reads.bytes, process.writes.bytes
+        );
+    }
+    let categories = trace.io_categories();
+    for &(name, count) in &[
+        ("Data reads", &categories.data_reads),
+        ("Metadata reads", &categories.metadata_reads),
+        ("Readahead", &categories.readahead),
+        ("Data writes", &categories.data_writes),
+        ("Metadata writes", &categories.metadata_writes),
+        ("Journal writes", &categories.journal_writes),
+        ("Flushes", &categories.flushes),
+        ("Discards", &categories.discards),
+        ("Sync writes", &categories.sync_writes),
+    ] {
+        println!(" - {}: {} requests, {} bytes", name, count.requests, count.bytes);
+    }
+    let breakdown = trace.latency_breakdown();
+    for &(name, phase) in &[
+        ("Q2G", &breakdown.q2g),
+        ("G2I", &breakdown.g2i),
+        ("I2D", &breakdown.i2d),
+        ("D2C", &breakdown.d2c),
+        ("Q2C", &breakdown.q2c),
+    ] {
+        println!(
+            " - {}: {} requests, mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+            name, phase.count, phase.latency.mean, phase.latency.p50, phase.latency.p99, phase.latency.max
+        );
+    }
+    let spatial = trace.spatial_stats();
+    println!(
+        " - Sequential: {} requests, random: {} requests ({:.1}% sequential, {} forward / {} backward seeks)",
+        spatial.sequential,
+        spatial.random,
+        spatial.sequential_ratio * 100.0,
+        spatial.forward_seeks,
+        spatial.backward_seeks
+    );
+    for bucket in &spatial.seek_histogram {
+        println!("   - seek [{}, {}) sectors: {}", bucket.lower, bucket.upper, bucket.count);
+    }
+    let queue = trace.queue_stats();
+    for &(name, sizes) in &[("Read", &queue.request_sizes.reads), ("Write", &queue.request_sizes.writes)] {
+        println!(" - {} requests: {}, mean size {:.0} bytes", name, sizes.count, sizes.mean);
+        for bucket in &sizes.histogram {
+            println!("   - [{}, {}) bytes: {}", bucket.lower, bucket.upper, bucket.count);
+        }
+    }
+    println!(
+        " - Merges: {} back, {} front, {} of {} queued bios ({:.1}%)",
+        queue.merges.backmerges,
+        queue.merges.frontmerges,
+        queue.merges.backmerges + queue.merges.frontmerges,
+        queue.merges.queued,
+        queue.merges.merge_ratio * 100.0
+    );
+    println!(" - Queue depth: mean {:.2}, max {}", queue.queue_depth.mean, queue.queue_depth.max);
+}
+
+// Reads blktrace_config.json from the working directory, if present, and applies the
+// command line overrides on top of it
+fn blktrace_config(matches: &clap::ArgMatches) -> Result<fsbench::blktrace::BlktraceConfig, String> {
+    use fsbench::blktrace::{parse_trace_mask, BlktraceConfig};
+    use std::str::FromStr;
+
+    fn number<T: FromStr>(matches: &clap::ArgMatches, name: &str, flag: &str) -> Result<Option<T>, String> {
+        match matches.value_of(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("--{} must be a non-negative integer", flag)),
+            None => Ok(None),
+        }
+    }
+
+    let mut config = BlktraceConfig::load("blktrace_config.json").unwrap_or(BlktraceConfig::default());
+    if let Some(kib) = number::<u32>(matches, "TRACE_BUFFER_SIZE", "trace-buffer-size")? {
+        let bytes = kib
+            .checked_mul(1024)
+            .ok_or_else(|| String::from("--trace-buffer-size must be less than 4194304 KiB"))?;
+        config = config.set_buffer_size(bytes);
+    }
+    if let Some(count) = number(matches, "TRACE_BUFFERS", "trace-buffers")? {
+        config = config.set_buffer_subbuffers(count);
+    }
+    if let Some(mask) = matches.value_of("TRACE_MASK") {
+        config = config.set_trace_mask(parse_trace_mask(mask)?);
+    }
+    if let Some(start_lba) = number(matches, "TRACE_START_LBA", "trace-start-lba")? {
+        config = config.set_start_lba(start_lba);
+    }
+    if let Some(end_lba) = number(matches, "TRACE_END_LBA", "trace-end-lba")? {
+        config = config.set_end_lba(end_lba);
+    }
+    if let Some(pid) = number(matches, "TRACE_PID", "trace-pid")? {
+        config = config.set_pid(pid);
+    }
+    if let Some(warmup) = number(matches, "WARMUP", "warmup")? {
+        config = config.set_warmup_ms(warmup);
+    }
+    if let Some(cooldown) = number(matches, "COOLDOWN", "cooldown")? {
+        config = config.set_cooldown_ms(cooldown);
+    }
+    if let Some(flush_wait) = number(matches, "FLUSH_WAIT", "flush-wait")? {
+        config = config.set_flush_wait_ms(flush_wait);
     }
+    if matches.is_present("NO_DROP_CACHE") {
+        config = config.set_drop_cache(false);
+    }
+    Ok(config)
+}
+
+// How often a benchmark is rerun with doubled blktrace buffers after losing events
+const MAX_TRACE_RETRIES: usize = 3;
+
+// Sets up the IO tracer selected with --io-tracer. blktrace starts tracing (BLKTRACESETUP and
+// BLKTRACESTART) right away, but only events recorded during the benchmarks are kept.
+fn io_tracer(
+    kind: fsbench::tracer::TracerKind,
+    devices: &[std::path::PathBuf],
+    config: fsbench::blktrace::BlktraceConfig,
+    debugfs_path: &str,
+    stream_traces: bool,
+    ftrace_events: Option<&[String]>,
+) -> Result<Box<fsbench::tracer::IoTracer>, String> {
+    use fsbench::blktrace::Blktrace;
+    use fsbench::diskstats::DiskStatsCollector;
+    use fsbench::ftrace::Ftrace;
+    use fsbench::tracer::*;
+    let disk_stats = DiskStatsCollector::new(devices);
+    let tracer: Box<IoTracer> = match kind {
+        TracerKind::Blktrace => {
+            let blktrace = Blktrace::new(devices.to_vec(), config, debugfs_path).map_err(|e| format!("failed to setup blktrace: {}", e))?;
+            Box::new(BlktraceTracer::new(blktrace, disk_stats, stream_traces))
+        }
+        TracerKind::DiskStats => {
+            // The block device stats are all we get, so they had better be readable
+            disk_stats
+                .snapshot()
+                .map_err(|e| format!("failed to read block device stats: {}", e))?;
+            Box::new(DiskStatsTracer::new(disk_stats))
+        }
+        TracerKind::None => Box::new(NoopTracer),
+    };
+    match ftrace_events {
+        Some(events) => {
+            let ftrace = Ftrace::new(debugfs_path, devices, events.to_vec()).map_err(|e| format!("failed to setup ftrace: {}", e))?;
+            Ok(Box::new(FtraceTracer::new(ftrace, tracer)))
+        }
+        None => Ok(tracer),
+    }
+}
+
+fn run(matches: &clap::ArgMatches) {
+    use fsbench::mount::Mount;
+    use fsbench::tracer::TracerKind;
+    use fsbench::util::{drop_cache, mkdir, mkfs, Filesystem};
+    use std::fs;
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    // Parse the filesystem and benchmark selections up front so a typo doesn't
+    // surface only after the first filesystem has been benchmarked
+    let filesystems: Vec<Filesystem> = match matches.values_of("FILESYSTEMS") {
+        Some(values) => {
+            let parsed: Result<Vec<Filesystem>, String> = values.map(|v| v.parse()).collect();
+            match parsed {
+                Ok(filesystems) => filesystems,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+        None if matches.is_present("DEVICE") => Filesystem::all(),
+        // Without a device the filesystem is whatever is mounted at --mount-path
+        None => vec![Filesystem::Mounted],
+    };
+    let repetitions: usize = match matches.value_of("REPETITIONS").unwrap_or("1").parse() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            error!("--repetitions must be a positive integer");
+            return;
+        }
+    };
+    let on_trace_loss = matches.value_of("ON_TRACE_LOSS").unwrap_or("warn");
+    // Must be set before any benchmark creates its Stats
+    fsbench::statistics::set_streaming(matches.is_present("STREAMING_STATS"));
+    fsbench::rusage::set_enabled(matches.is_present("RESOURCE_USAGE"));
+
+    let registry = benchmarks::registry();
+    let selected_benchmarks: Vec<&benchmarks::Registration> = match matches.values_of("BENCHMARKS") {
+        Some(values) => {
+            let mut selected = Vec::new();
+            for name in values {
+                match registry.iter().find(|registration| registration.name == name) {
+                    Some(registration) => selected.push(registration),
+                    None => {
+                        error!("unknown benchmark `{}`", name);
+                        return;
+                    }
+                }
+            }
+            selected
+        }
+        None => registry.iter().collect(),
+    };
 
     // Get the command line arguments
     // device = the block device to test (e.g. /dev/sda1, /dev/nvme0n1)
-    // We expect the device to _not_ be mounted
-    let device = matches.value_of("DEVICE").expect("No device specified");
+    // We expect the device to _not_ be mounted. Without one, the benchmarks run in the filesystem
+    // mounted at --mount-path, which isn't recreated between benchmarks.
+    let device = matches.value_of("DEVICE");
+    let tracer_kind: TracerKind = match matches.value_of("IO_TRACER").unwrap_or("blktrace").parse() {
+        Ok(kind) => kind,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if device.is_none() {
+        if tracer_kind == TracerKind::Blktrace {
+            error!("--io-tracer blktrace needs --device; use diskstats or none for an already mounted filesystem");
+            return;
+        }
+        if !matches.is_present("MOUNT_PATH") {
+            error!("--mount-path is required without --device");
+            return;
+        }
+        if matches.is_present("FILESYSTEMS") {
+            error!("--fs needs --device to create the filesystems on");
+            return;
+        }
+    }
+
+    // we need to be root to use blktrace, ftrace and mount filesystems
+    let uid = nix::unistd::geteuid();
+    let ftrace = matches.is_present("FTRACE") || matches.is_present("FTRACE_EVENTS");
+    if (device.is_some() || ftrace) && !uid.is_root() {
+        error!("Need to be root");
+        return;
+    }
+    // Off by default on recent kernels; without it every operation's iowait is 0
+    fsbench::taskstats::enable();
 
     // Create a temporary directory. The device will be mounted here
     let tempdir = tempdir::TempDir::new("benchmarks").expect("failed to create temporary directory");
@@ -94,155 +573,213 @@ fn main() {
     // The path where debugfs is mounted. This is used for blktrace
     let debugfs_path = matches.value_of("DEBUGFS").unwrap_or("/sys/kernel/debug");
 
-    // Start blktrace. This will call BLKTRACESETUP and BLKTRACESTART so IO events
-    // will start showing up. However we will only consider events that occur during the benchmarks
-    let blktrace = Blktrace::new(PathBuf::from(device), BlktraceConfig::default(), debugfs_path).expect("failed to setup blktrace");
-
-
-
-
-
-    let filesystems = [Filesystem::Ext4, Filesystem::Ext4NoJournal, Filesystem::F2fs, Filesystem::Btrfs, Filesystem::Xfs, Filesystem::Ext2];
-    for fstype in filesystems.into_iter() {
-        let base_config = benchmarks::BaseConfiguration {
-            filesystem_path: &filesystem_path,
-            blktrace: &blktrace,
-            output_dir: output_dir.join(fstype.to_string()),
-        };
-
-        drop_cache();
-
-        // Standard createfiles test with no fsync
-        let createfiles_config =
-            benchmarks::CreateFilesConfig::load("createfiles_config.json").unwrap_or(benchmarks::CreateFilesConfig::config_for(fstype));
-
-        let createfiles = {
-            mkfs(device, fstype);
-            let _m = Mount::new(device, filesystem_path_str);
-            info!("Running create test (end sync)..");
-            let createfiles = benchmarks::CreateFiles::run(&base_config, &createfiles_config);
-            createfiles.export().expect("failed to export benchmark data");
-            createfiles
-        };
-
-        let createfiles_sync_config = benchmarks::CreateFilesBatchSyncConfig::load("createfiles_batchsync.json")
-            .unwrap_or(benchmarks::CreateFilesBatchSyncConfig::config_for(fstype));
-        let createfiles_sync = {
-            mkfs(device, fstype);
-            let _m = Mount::new(device, filesystem_path_str);
-            // Create files, but fsync after every 10 files
-            info!("Running create test (intermittent fsync)..");
-            let createfiles_sync = benchmarks::CreateFilesBatchSync::run(&base_config, &createfiles_sync_config);
-            createfiles_sync.export().expect("failed to export benchmark data");
-            createfiles_sync
-        };
+    let blktrace_config = match blktrace_config(matches) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let mut trace_devices: Vec<PathBuf> = device.into_iter().map(PathBuf::from).collect();
+    if let Some(values) = matches.values_of("TRACE_DEVICES") {
+        trace_devices.extend(values.map(PathBuf::from));
+    }
+    // The block device stats of a mounted filesystem are those of the device it is on
+    if trace_devices.is_empty() && tracer_kind == TracerKind::DiskStats {
+        match fsbench::diskstats::backing_device(&filesystem_path) {
+            Ok(backing) => trace_devices.push(backing),
+            Err(e) => {
+                error!("{}; use --io-tracer none or --trace-device", e);
+                return;
+            }
+        }
+    }
+    let stream_traces = matches.is_present("STREAM_TRACES");
+    let ftrace_events: Option<Vec<String>> = match matches.values_of("FTRACE_EVENTS") {
+        Some(values) => Some(values.map(String::from).collect()),
+        None if matches.is_present("FTRACE") => Some(fsbench::ftrace::DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect()),
+        None => None,
+    };
+    let new_tracer = |config| {
+        io_tracer(
+            tracer_kind,
+            &trace_devices,
+            config,
+            debugfs_path,
+            stream_traces,
+            ftrace_events.as_ref().map(|events| &events[..]),
+        )
+    };
+    let mut tracer = match new_tracer(blktrace_config) {
+        Ok(tracer) => tracer,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
-        let createfiles_eachsync_config = benchmarks::CreateFilesEachSyncConfig::load("createfiles_eachsync.json")
-            .unwrap_or(benchmarks::CreateFilesEachSyncConfig::config_for(fstype));
-        let createfiles_eachsync = {
-            mkfs(device, fstype);
-            let _m = Mount::new(device, filesystem_path_str);
-            // Create files, but fsync after every file
-            info!("Running create test (frequent fsync)..");
-            let createfiles_eachsync = benchmarks::CreateFilesEachSync::run(&base_config, &createfiles_eachsync_config);
-            createfiles_eachsync.export().expect("failed to export benchmark data");
-            createfiles_eachsync
-        };
+    for fstype in filesystems.iter() {
+        let fs_output_dir = output_dir.join(fstype.to_string());
+        // runs[i] holds the summaries of every repetition of the i-th selected benchmark
+        let mut runs: Vec<Vec<benchmarks::Summary>> = selected_benchmarks.iter().map(|_| Vec::new()).collect();
 
-        let renamefiles_config =
-            benchmarks::RenameFilesConfig::load("renamefiles_config.json").unwrap_or(benchmarks::RenameFilesConfig::config_for(fstype));
-        let renamefiles = {
-            mkfs(device, fstype);
-            let _m = Mount::new(device, filesystem_path_str);
-            // Rename files test
-            info!("Running rename test..");
-            let renamefiles = benchmarks::RenameFiles::run(&base_config, &renamefiles_config);
-            renamefiles.export().expect("failed to export benchmark data");
-            renamefiles
-        };
+        for repetition in 0..repetitions {
+            // A single run keeps the flat layout; repeated runs each get their own directory
+            let repetition_output_dir = if repetitions > 1 {
+                info!("Starting repetition {} of {} on {}", repetition + 1, repetitions, fstype.to_string());
+                fs_output_dir.join(format!("rep{}", repetition))
+            } else {
+                fs_output_dir.clone()
+            };
+            drop_cache();
+            let mut info = Vec::new();
 
-        let deletefiles_config =
-            benchmarks::DeleteFilesConfig::load("deletefiles_config.json").unwrap_or(benchmarks::DeleteFilesConfig::config_for(fstype));
-        let deletefiles =  {
-            mkfs(device, fstype);
-            let _m = Mount::new(device, filesystem_path_str);
-            // Delete files test
-            // NOTE: filebench has a removedirs.f workload, but this actually only calls rmdir() and _does not_
-            // recursively delete files
-            info!("Running delete test..");
-            let deletefiles = benchmarks::DeleteFiles::run(&base_config, &deletefiles_config);
-            deletefiles.export().expect("failed to export benchmark data");
-            deletefiles
-        };
+            for registration in &selected_benchmarks {
+                let mut retries = 0;
+                // Retries double the buffers of this benchmark only
+                let mut run_config = blktrace_config;
+                loop {
+                    let summary = {
+                        let _m = device.map(|device| {
+                            mkfs(device, fstype);
+                            Mount::new(device, filesystem_path_str)
+                        });
+                        // A filesystem that isn't recreated gets a fresh directory for every benchmark instead
+                        let benchmark_path = match device {
+                            Some(_) => filesystem_path.clone(),
+                            None => {
+                                let path = filesystem_path.join(format!("fsbench-{}", registration.name));
+                                if path.exists() {
+                                    fs::remove_dir_all(&path).expect("failed to remove an old benchmark directory");
+                                }
+                                mkdir(&path).expect("failed to create benchmark directory");
+                                path
+                            }
+                        };
+                        let base_config = benchmarks::BaseConfiguration {
+                            filesystem_path: &benchmark_path,
+                            tracer: &*tracer,
+                            output_dir: repetition_output_dir.clone(),
+                        };
+                        let mut benchmark = (registration.new)(fstype);
+                        info!("Running {} benchmark..", benchmark.name());
+                        benchmark.prepare(&base_config);
+                        benchmark.run(&base_config);
+                        benchmark.teardown(&base_config);
+                        benchmark
+                            .export(&base_config.output_dir.join(benchmark.name()))
+                            .expect("failed to export benchmark data");
+                        benchmark
+                            .export_time_series(&base_config.output_dir.join(benchmark.name()))
+                            .expect("failed to export time series");
+                        if device.is_none() {
+                            if let Err(e) = fs::remove_dir_all(&benchmark_path) {
+                                warn!("failed to remove {:?}: {}", benchmark_path, e);
+                            }
+                        }
+                        benchmark.summary()
+                    };
+                    if let Some(ref fs_events) = summary.fs_events {
+                        info!(
+                            "{}: {} fsyncs, {} journal commits{}",
+                            summary.name,
+                            fs_events.fsyncs,
+                            fs_events.journal_commits,
+                            fs_events
+                                .commits_per_fsync
+                                .map_or(String::new(), |ratio| format!(" ({:.3} per fsync)", ratio))
+                        );
+                        if fs_events.lost_events > 0 {
+                            warn!("ftrace lost {} events during {}", fs_events.lost_events, summary.name);
+                        }
+                    }
+                    if let Some(ref check) = summary.disk_stats_check {
+                        if !check.consistent {
+                            warn!(
+                                "blktrace and the block device stats disagree for {}: reads {} vs {} bytes, writes {} vs {} bytes",
+                                summary.name, check.trace_reads, check.diskstats_reads, check.trace_writes, check.diskstats_writes
+                            );
+                        }
+                    }
+                    if summary.complete {
+                        info.push(summary);
+                        break;
+                    }
+                    let missing = summary.trace_loss.missing_events();
+                    match on_trace_loss {
+                        "fail" => {
+                            error!("blktrace lost {} events during {}", missing, summary.name);
+                            return;
+                        }
+                        "retry" if retries < MAX_TRACE_RETRIES => {
+                            retries += 1;
+                            // The buffers are allocated by BLKTRACESETUP, so tracing has to be restarted
+                            run_config = run_config.set_buffer_size(run_config.buffer_size().saturating_mul(2));
+                            warn!(
+                                "blktrace lost {} events during {}, retrying with {} KiB buffers",
+                                missing,
+                                summary.name,
+                                run_config.buffer_size() / 1024
+                            );
+                            drop(tracer);
+                            tracer = match new_tracer(run_config) {
+                                Ok(tracer) => tracer,
+                                Err(e) => {
+                                    error!("{}", e);
+                                    return;
+                                }
+                            };
+                        }
+                        _ => {
+                            warn!("blktrace lost {} events during {}, results are incomplete", missing, summary.name);
+                            info.push(summary);
+                            break;
+                        }
+                    }
+                }
+                // The next benchmark starts with the configured buffers again
+                if retries > 0 {
+                    drop(tracer);
+                    tracer = match new_tracer(blktrace_config) {
+                        Ok(tracer) => tracer,
+                        Err(e) => {
+                            error!("{}", e);
+                            return;
+                        }
+                    };
+                }
+            }
 
-        let listdir_config = benchmarks::ListDirConfig::load("listdir_config.json").unwrap_or(benchmarks::ListDirConfig::config_for(fstype));
-        let listdir = {
-            mkfs(device, fstype);
-            let _m = Mount::new(device, filesystem_path_str);
-            // Listdir test
-            info!("Running listdir test..");
-            let listdir = benchmarks::ListDir::run(&base_config, &listdir_config);
-            listdir.export().expect("failed to export benchmark data");
-            listdir
-        };
+            serde_json::to_writer(
+                File::create(repetition_output_dir.join("summary.json")).expect("failed to create file"),
+                &info,
+            ).expect("failed to write to summary json");
 
-        /*
-        // Varmail test, based off varmail.f from filebench
-        info!("Running varmail test..");
-        let varmail_config = benchmarks::VarmailConfig::load("varmail_config.json").unwrap_or(benchmarks::VarmailConfig::config_for(fstype));
-        let varmail = benchmarks::Varmail::run(&base_config, &varmail_config);
-        varmail.export().expect("failed to export benchmark data");
-         */
+            for (index, summary) in info.into_iter().enumerate() {
+                runs[index].push(summary);
+            }
+        }
 
-        use std::fs::File;
-        let info = vec![
-            get_summary("createfiles", &createfiles),
-            get_summary("createfiles_batchsync", &createfiles_sync),
-            get_summary("createfiles_eachsync", &createfiles_eachsync),
-            get_summary("renamefiles", &renamefiles),
-            get_summary("deletefiles", &deletefiles),
-            get_summary("listdir", &listdir),
-        ];
-        serde_json::to_writer(
-            File::create(base_config.output_dir.join("summary.json")).expect("failed to create file"),
-            &info,
-        ).expect("failed to write to summary json");
+        if repetitions > 1 {
+            let aggregates: Vec<benchmarks::AggregateSummary> =
+                runs.iter().map(|r| benchmarks::AggregateSummary::from_runs(r)).collect();
+            for aggregate in &aggregates {
+                info!("{} on {} over {} runs:", aggregate.name, fstype.to_string(), aggregate.repetitions);
+                info!(" - Duration (s): {}", aggregate.duration);
+                info!(" - Reads (bytes): {}", aggregate.reads);
+                info!(" - Writes (bytes): {}", aggregate.writes);
+                info!(" - Iowait (ns): {}", aggregate.iowait);
+            }
+            serde_json::to_writer(
+                File::create(fs_output_dir.join("aggregate.json")).expect("failed to create file"),
+                &aggregates,
+            ).expect("failed to write to aggregate json");
+        }
     }
 
-
     // Blktrace will be stopped by its destructor
 }
 
-#[derive(Serialize)]
-struct Summary {
-    name: String,
-    duration: Duration,
-    io_duration: Duration,
-    io_requests: usize,
-    operations: usize,
-    reads: usize,
-    writes: usize,
-    num_files: usize,
-    iowait: usize,
-}
-
-fn get_summary<C: benchmarks::Config, B: benchmarks::Benchmark<C>>(name: &str, benchmark: &B) -> Summary {
-    let total = benchmark.total();
-    let reads = benchmark.get_trace().completed_reads();
-    let writes = benchmark.get_trace().completed_writes();
-    Summary {
-        name: name.to_owned(),
-        duration: total.total_latency(),
-        io_duration: benchmark.get_trace().io_duration(),
-        io_requests: benchmark.get_trace().num_requests(),
-        operations: total.num_ops(),
-        reads: reads,
-        writes: writes,
-        num_files: benchmark.get_config().num_files(),
-        iowait: total.total_iowait(),
-    }
-}
-
 fn setup_logger() -> Result<(), fern::InitError> {
     use fern::colors::{Color, ColoredLevelConfig};
     fern::Dispatch::new()